@@ -4,6 +4,7 @@
 use sails_rs::prelude::*;
 use session_service::*; 
 
+pub mod math;
 pub mod services;
 
 use services::service::{Service, ActionsForSession};
@@ -20,15 +21,18 @@ impl Program {
     /// `debt_token` - address of the VFT contract used as borrow (debt) asset.
     /// `base_interest_rate` - annual interest rate in 1e18 decimals (e.g. 3% = 3_000_000_000_000_000_000).
     /// `min_loan`, `max_loan` - principal limits, in debt token smallest units.
-    pub fn new(
+    /// `max_loans` - cap on the number of stored loans (defaults to 10,000).
+    /// Both tokens are queried for their decimals, which must match.
+    pub async fn new(
         collateral_token: ActorId,
         debt_token: ActorId,
         base_interest_rate: u128,
         min_loan: u128,
         max_loan: u128,
+        max_loans: Option<u32>,
         config: Config,
     ) -> Self {
-        Service::seed(collateral_token, debt_token, base_interest_rate, min_loan, max_loan);
+        Service::seed(collateral_token, debt_token, base_interest_rate, min_loan, max_loan, max_loans).await;
         SessionService::init(config);
         Self
     }
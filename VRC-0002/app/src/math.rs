@@ -0,0 +1,163 @@
+//! Fixed-point helpers shared by the lending service.
+//!
+//! Values are unsigned integers scaled by a caller-chosen `scale`
+//! (e.g. `1e18` for DECIMALS_FACTOR). Intermediate products are computed
+//! in 256 bits, and results that do not fit in `u128` saturate to `u128::MAX`.
+
+use sails_rs::prelude::U256;
+
+/// `a * b / denom`, rounded down, without intermediate overflow.
+/// Panics if `denom` is zero.
+pub fn mul_div(a: u128, b: u128, denom: u128) -> u128 {
+    if denom == 0 {
+        panic!("Division by zero");
+    }
+    let result = U256::from(a) * U256::from(b) / U256::from(denom);
+    if result > U256::from(u128::MAX) {
+        u128::MAX
+    } else {
+        result.as_u128()
+    }
+}
+
+/// `a * b / denom`, rounded up, without intermediate overflow.
+/// Panics if `denom` is zero.
+pub fn mul_div_up(a: u128, b: u128, denom: u128) -> u128 {
+    if denom == 0 {
+        panic!("Division by zero");
+    }
+    let denom = U256::from(denom);
+    let result = (U256::from(a) * U256::from(b) + denom - 1) / denom;
+    if result > U256::from(u128::MAX) {
+        u128::MAX
+    } else {
+        result.as_u128()
+    }
+}
+
+/// `a * b * c / denom`, rounded down once at the end, without intermediate overflow.
+/// Panics if `denom` is zero. When `a * b * c` does not fit in 256 bits the quotient is at
+/// least `2^256 / u128::MAX > u128::MAX`, so saturating without dividing gives the same
+/// result as exact arithmetic.
+pub fn mul3_div(a: u128, b: u128, c: u128, denom: u128) -> u128 {
+    if denom == 0 {
+        panic!("Division by zero");
+    }
+    let result = (U256::from(a) * U256::from(b))
+        .checked_mul(U256::from(c))
+        .map_or(U256::MAX, |product| product / U256::from(denom));
+    if result > U256::from(u128::MAX) {
+        u128::MAX
+    } else {
+        result.as_u128()
+    }
+}
+
+/// `base ^ exp` where `base` and the result are fixed-point numbers with `scale` as one.
+/// `pow_fixed(x, 0, scale) == scale` for any `x`. Uses square-and-multiply, rounding down
+/// at each step.
+pub fn pow_fixed(base: u128, exp: u32, scale: u128) -> u128 {
+    let mut result = scale;
+    let mut base = base;
+    let mut exp = exp;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_div(result, base, scale);
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = mul_div(base, base, scale);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCALE: u128 = 1_000_000_000_000_000_000;
+
+    #[test]
+    fn mul_div_identity() {
+        assert_eq!(mul_div(12_345, SCALE, SCALE), 12_345);
+        assert_eq!(mul_div(0, u128::MAX, 1), 0);
+        assert_eq!(mul_div(u128::MAX, 1, 1), u128::MAX);
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+    }
+
+    #[test]
+    fn mul_div_rounds_down_and_up() {
+        assert_eq!(mul_div(10, 1, 3), 3);
+        assert_eq!(mul_div_up(10, 1, 3), 4);
+        // Exact quotients are the same either way
+        assert_eq!(mul_div(9, 1, 3), 3);
+        assert_eq!(mul_div_up(9, 1, 3), 3);
+        assert_eq!(mul_div_up(0, 5, 7), 0);
+    }
+
+    #[test]
+    fn mul_div_saturates() {
+        assert_eq!(mul_div(u128::MAX, 2, 1), u128::MAX);
+        assert_eq!(mul_div_up(u128::MAX, u128::MAX, 1), u128::MAX);
+        // Just below the boundary still fits exactly
+        assert_eq!(mul_div(u128::MAX, 2, 2), u128::MAX);
+        assert_eq!(mul_div_up(u128::MAX, 2, 2), u128::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "Division by zero")]
+    fn mul_div_zero_denom_panics() {
+        mul_div(1, 1, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Division by zero")]
+    fn mul_div_up_zero_denom_panics() {
+        mul_div_up(1, 1, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Division by zero")]
+    fn mul3_div_zero_denom_panics() {
+        mul3_div(1, 1, 1, 0);
+    }
+
+    #[test]
+    fn mul3_div_rounds_once() {
+        // Two separate roundings would give 0 here
+        assert_eq!(mul3_div(3, 3, 3, 9), 3);
+        assert_eq!(mul3_div(1, 1, 2, 3), 0);
+        assert_eq!(mul3_div(2, 2, 2, 3), 2);
+        assert_eq!(mul3_div(7, SCALE, 1, SCALE), 7);
+    }
+
+    #[test]
+    fn mul3_div_overflow_boundaries() {
+        // a * b * c fits in 256 bits and the quotient fits in u128
+        assert_eq!(mul3_div(u128::MAX, u128::MAX, 1, u128::MAX), u128::MAX);
+        assert_eq!(mul3_div(1 << 64, 1 << 64, 1 << 63, 1 << 64), 1 << 127);
+        // The quotient is 2^128, one past u128::MAX
+        assert_eq!(mul3_div(1 << 64, 1 << 64, 1 << 64, 1 << 64), u128::MAX);
+        // The product overflows 256 bits even with the largest denominator
+        assert_eq!(mul3_div(u128::MAX, u128::MAX, 2, u128::MAX), u128::MAX);
+        assert_eq!(mul3_div(u128::MAX, u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+    }
+
+    #[test]
+    fn pow_fixed_identities() {
+        assert_eq!(pow_fixed(0, 0, SCALE), SCALE);
+        assert_eq!(pow_fixed(5 * SCALE, 0, SCALE), SCALE);
+        assert_eq!(pow_fixed(5 * SCALE, 1, SCALE), 5 * SCALE);
+        assert_eq!(pow_fixed(SCALE, 1_000, SCALE), SCALE);
+        assert_eq!(pow_fixed(0, 3, SCALE), 0);
+    }
+
+    #[test]
+    fn pow_fixed_values() {
+        assert_eq!(pow_fixed(2 * SCALE, 10, SCALE), 1_024 * SCALE);
+        assert_eq!(pow_fixed(SCALE / 2, 2, SCALE), SCALE / 4);
+        // Saturates instead of overflowing
+        assert_eq!(pow_fixed(u128::MAX, 2, 1), u128::MAX);
+    }
+}
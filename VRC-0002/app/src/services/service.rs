@@ -12,11 +12,44 @@ use sails_rs::collections::HashMap as SailsHashMap;
 
 // ---- Signless/session 
 use crate::{SessionData, Storage};
+use crate::math::{mul3_div, mul_div, mul_div_up, pow_fixed};
 
 // ---- State Definitions ----
 
 const DECIMALS_FACTOR: u128 = 1_000_000_000_000_000_000; // 1e18
-const MIN_COLLATERAL_RATIO: u128 = 150_000_000_000_000_000_000; // 150%
+const DEFAULT_MIN_COLLATERAL_RATIO: u128 = 150_000_000_000_000_000_000; // 150%
+const MAX_SEED_BATCH: usize = 100;
+const MAX_USER_LOANS: usize = 100;
+const DEFAULT_MAX_LOANS: u32 = 10_000;
+/// Bumped whenever the parameter layout exposed by `query_config` changes meaningfully
+const CONFIG_VERSION: u16 = 1;
+const DAY_MS: u64 = 86_400_000;
+/// 365 days in milliseconds, the unit of `exec::block_timestamp`
+const YEAR_MS: u128 = 31_536_000_000;
+const MAX_REINDEX_BATCH: u64 = 1_000;
+const ORACLE_GAS_LIMIT: u64 = 2_000_000_000;
+/// Oldest cached price `fetch_price` falls back to when the oracle fails
+const DEFAULT_MAX_PRICE_AGE_MS: u64 = 3_600_000;
+const REPLY_TIMEOUT_BLOCKS: u32 = 10;
+const DEFAULT_CALL_GAS_LIMIT: u64 = 5_000_000_000;
+/// Rough gas used by a command outside its external calls, for `query_estimated_gas`
+const OP_GAS_OVERHEAD: u64 = 1_000_000_000;
+const MAX_CONSOLIDATE_LOANS: usize = 20;
+const MAX_RATE_HISTORY: usize = 100;
+const MAX_FLOATING_CHECKPOINTS: usize = 100;
+const MAX_DASHBOARD_LOANS: u32 = 50;
+const MAX_PAUSE_REASON_LEN: usize = 128;
+const MAX_FULL_STATE_LOANS: usize = 2_000;
+/// Upper bound on the items any paginating query returns, whatever `limit` it is given
+const MAX_QUERY_ITEMS: u32 = 100;
+const MAX_REBASE_BATCH: usize = 50;
+/// Partial liquidations kept per loan; older records are dropped first
+const MAX_LIQUIDATION_RECORDS: usize = 10;
+const MAX_EMERGENCY_BATCH: u32 = 50;
+/// Largest debt-token drift (smallest units) `sync_rounding_reserve` absorbs as rounding
+const MAX_ROUNDING_RESERVE: u128 = 1_000_000;
+/// Capitalizations per year `query_effective_apy` assumes when interest capitalization is on
+const CAPITALIZATIONS_PER_YEAR: u32 = 365;
 
 static mut LENDING_STATE: Option<LendingState> = None;
 
@@ -30,6 +63,27 @@ pub enum LoanStatus {
     Liquidated,
 }
 
+/// How a loan's rate evolves: `Fixed` keeps the rate snapshotted at open, `Floating`
+/// follows the current borrow rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub enum RateMode {
+    Fixed,
+    Floating,
+}
+
+/// What `liquidate` does with an undercollateralized loan: `Seize` takes all of its collateral
+/// and closes it, `Restore` repays just enough debt to bring it back to `min_collateral_ratio`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub enum LiquidationMode {
+    #[default]
+    Seize,
+    Restore,
+}
+
 /// Loan struct
 #[derive(Debug, Clone, Encode, Decode, TypeInfo)]
 #[codec(crate = sails_rs::scale_codec)]
@@ -38,11 +92,55 @@ pub struct Loan {
     pub borrower: ActorId,
     pub collateral: u128,
     pub principal: u128,
-    pub interest_rate: u128, // per year, in DECIMALS_FACTOR
-    pub start_block: u64,
+    /// Per year, in DECIMALS_FACTOR; for floating loans, the rate at the last settlement
+    pub interest_rate: u128,
+    /// Block timestamp in milliseconds the loan was opened at
+    pub start_timestamp: u64,
+    /// Block timestamp in milliseconds of repay/liquidation, None while active
+    pub closed_at: Option<u64>,
+    /// Interest settled up to `last_accrual_ts`
+    pub accrued_so_far: u128,
+    /// Interest rolled into `principal` by `capitalize_interest`; still counted as interest
+    /// for the interest cap and the reserve share
+    pub capitalized_interest: u128,
+    /// Block timestamp in milliseconds interest has been settled up to
+    pub last_accrual_ts: u64,
+    /// Block timestamp in milliseconds the term ends at, None for perpetual loans
+    pub maturity_ts: Option<u64>,
+    /// Collateral held aside to top the loan up before liquidation
+    pub protection_deposit: u128,
+    pub rate_mode: RateMode,
+    /// Block timestamp in milliseconds of the last collateral withdrawal, 0 if none
+    pub last_withdraw_ts: u64,
+    /// Latest partial liquidations, oldest first
+    pub liquidations: Vec<LiquidationRecord>,
     pub status: LoanStatus,
 }
 
+/// One partial liquidation of a loan
+#[derive(Debug, Clone, Encode, Decode, TypeInfo)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct LiquidationRecord {
+    pub timestamp: u64,
+    pub debt_repaid: u128,
+    pub collateral_seized: u128,
+    pub liquidator: ActorId,
+}
+
+/// Pre-formed loan imported by `seed_loans` (tokens assumed already settled)
+#[derive(Debug, Clone, Encode, Decode, TypeInfo)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct SeedLoan {
+    pub borrower: ActorId,
+    pub collateral: u128,
+    pub principal: u128,
+    pub interest_rate: u128,
+    pub start_ts: u64,
+    pub maturity_ts: Option<u64>,
+}
+
 /// Lending state struct
 #[derive(Debug, Clone, Default)]
 pub struct LendingState {
@@ -57,6 +155,78 @@ pub struct LendingState {
     pub user_loans: SailsHashMap<ActorId, Vec<u64>>,
     pub total_collateral: u128,
     pub total_principal: u128,
+    pub interest_free_period_ms: u64,
+    pub migration_mode: bool,
+    pub max_loans: u32,
+    pub min_open_interval_ms: u64,
+    pub last_open_ts: SailsHashMap<ActorId, u64>,
+    pub pending_opens: SailsHashMap<ActorId, u32>, // loan slots held by opens still awaiting their transfers
+    pub bad_debt: u128,
+    pub origination_fee: u128, // fraction of principal, in DECIMALS_FACTOR
+    pub referral_fee: u128,    // fraction of the origination fee, in DECIMALS_FACTOR
+    pub protocol_reserve: u128,
+    pub price_oracle: Option<ActorId>,
+    pub cached_prices: SailsHashMap<ActorId, (u128, u64)>, // (price, timestamp fetched)
+    pub max_price_age_ms: u64, // oldest cached price used when the oracle fails; 0 = never fall back
+    pub total_liquidity: u128,
+    pub lp_balances: SailsHashMap<ActorId, u128>,
+    pub reserve_factor: u128, // share of repaid interest kept by the protocol, in DECIMALS_FACTOR
+    pub cumulative_revenue: u128,
+    pub min_liquidation_principal: u128,
+    pub config_version: u16,
+    pub collateral_factor: u128, // share of collateral value usable as borrowing power, in DECIMALS_FACTOR
+    pub liquidator_reward: u128, // share of seized collateral paid to the liquidator, in DECIMALS_FACTOR
+    pub daily_reward_cap: u128,
+    pub rewards_paid_today: u128,
+    pub reward_day: u64,
+    pub permissioned_liquidation: bool,
+    pub liquidators: Vec<ActorId>,
+    pub collateral_decimals: u8,
+    pub debt_decimals: u8,
+    pub tokens_verified: bool,
+    pub max_collateral_ratio: u128, // 0 = unlimited
+    pub min_interest_rate: u128,
+    pub max_interest_rate: u128, // 0 = no ceiling
+    pub total_debt_issued: u128,
+    pub total_debt_burned: u128,
+    pub max_session_lifetime_ms: u64, // 0 = unlimited
+    pub close_factor: u128, // max share of the debt one `liquidate_amount` may cover, in DECIMALS_FACTOR
+    pub liquidation_bonus: u128, // extra collateral seized on top of the covered debt, in DECIMALS_FACTOR
+    pub max_liquidations_per_window: u32, // 0 = unlimited
+    pub liquidation_window_ms: u64,
+    pub liquidation_window_start: u64,
+    pub liquidations_in_window: u32,
+    pub accrual_start_delay_ms: u64,
+    pub loan_term_ms: u64, // 0 = perpetual loans
+    pub profiling: bool,
+    pub max_fee_ratio: u128, // max upfront fees as a share of principal, in DECIMALS_FACTOR; 0 = unlimited
+    pub auto_compound_reserve: bool,
+    pub compounded_reserve: u128, // reserve recycled into `total_liquidity`, owned by the protocol
+    pub rate_history: Vec<(u64, u128)>, // (timestamp, base rate), oldest first, at most MAX_RATE_HISTORY
+    pub max_liquidation_bonus_abs: u128, // cap on the bonus in collateral units; 0 = uncapped
+    pub call_gas_limit: u64, // gas forwarded with each token call
+    pub admin_nonce: u64, // expected nonce of the next owner-only call
+    pub collateral_strategy: Option<ActorId>,
+    pub strategy_enabled: bool, // new collateral is deployed only while enabled
+    pub collateral_in_strategy: u128,
+    pub collateral_withdraw_cooldown_ms: u64,
+    pub min_collateral_topup: u128,   // top-ups at least this large are always accepted (0 = none)
+    pub min_health_improvement: u128, // otherwise the health factor must rise by this much
+    pub paused: bool,                 // blocks new loans; repay and liquidation stay open
+    pub pause_reason: Option<Vec<u8>>,
+    pub max_utilization_for_borrow: u128, // in DECIMALS_FACTOR, 0 = no limit
+    pub liquidation_surplus: SailsHashMap<ActorId, u128>, // claimable collateral: liquidation leftovers and refused payouts
+    pub total_liquidation_surplus: u128,
+    pub liquidation_counts: SailsHashMap<ActorId, u32>, // loans of each borrower closed by liquidation
+    pub max_interest_multiple: u128, // cap on a loan's interest relative to its principal, 0 = uncapped
+    pub liquidation_mode: LiquidationMode,
+    pub post_maturity_grace_ms: u64, // window after maturity in which a healthy term loan can still be repaid
+    pub min_collateral_ratio: u128, // health factor below which loans are liquidatable, in DECIMALS_FACTOR
+    pub interest_capitalization: bool, // allows `capitalize_interest` to roll interest into principal
+    pub rounding_reserve: u128, // debt tokens held beyond the books, from rounding; see `sync_rounding_reserve`
+    pub action_allowlist: Option<Vec<ActionsForSession>>, // actions enabled for session calls, None = all
+    pub liquidity_pool_enabled: bool, // loans are capped by LP liquidity and repaid principal returns to it
+    pub floating_rate_checkpoints: Vec<(u64, u128, u128)>, // (timestamp, rate index, new rate) per floating rate change, at most MAX_FLOATING_CHECKPOINTS
 }
 
 impl LendingState {
@@ -67,6 +237,7 @@ impl LendingState {
         base_interest_rate: u128,
         min_loan: u128,
         max_loan: u128,
+        max_loans: u32,
     ) {
         unsafe {
             LENDING_STATE = Some(Self {
@@ -76,19 +247,27 @@ impl LendingState {
                 base_interest_rate,
                 min_loan,
                 max_loan,
+                max_loans,
+                config_version: CONFIG_VERSION,
+                collateral_factor: DECIMALS_FACTOR,
+                close_factor: DECIMALS_FACTOR,
+                call_gas_limit: DEFAULT_CALL_GAS_LIMIT,
+                min_collateral_ratio: DEFAULT_MIN_COLLATERAL_RATIO,
+                max_price_age_ms: DEFAULT_MAX_PRICE_AGE_MS,
                 ..Default::default()
             })
         }
     }
+    pub fn is_initialized() -> bool {
+        unsafe { LENDING_STATE.is_some() }
+    }
     pub fn state_mut() -> &'static mut LendingState {
         let s = unsafe { LENDING_STATE.as_mut() };
-        debug_assert!(s.is_some(), "LendingState not initialized");
-        unsafe { s.unwrap_unchecked() }
+        s.unwrap_or_else(|| panic!("{:?}", LendingError::NotInitialized))
     }
     pub fn state_ref() -> &'static LendingState {
         let s = unsafe { LENDING_STATE.as_ref() };
-        debug_assert!(s.is_some(), "LendingState not initialized");
-        unsafe { s.unwrap_unchecked() }
+        s.unwrap_or_else(|| panic!("{:?}", LendingError::NotInitialized))
     }
 }
 
@@ -97,15 +276,18 @@ impl LendingState {
 #[codec(crate = sails_rs::scale_codec)]
 #[scale_info(crate = sails_rs::scale_info)]
 pub enum LendingEvent {
+    /// `via_key` is the session key that acted for `borrower`, if any.
     LoanOpened {
         loan_id: u64,
         borrower: ActorId,
         collateral: u128,
         principal: u128,
+        via_key: Option<ActorId>,
     },
     Repaid {
         loan_id: u64,
         borrower: ActorId,
+        via_key: Option<ActorId>,
     },
     Liquidated {
         loan_id: u64,
@@ -113,6 +295,172 @@ pub enum LendingEvent {
     },
     OwnerSet(ActorId),
     ParamsUpdated,
+    MigrationModeSet(bool),
+    LoansSeeded {
+        count: u32,
+    },
+    TotalsChanged {
+        total_collateral: u128,
+        total_principal: u128,
+    },
+    UserIndexRebuilt {
+        next_loan_id: u64,
+        complete: bool,
+    },
+    BadDebtRealized {
+        loan_id: u64,
+        amount: u128,
+    },
+    TotalsReconciled {
+        total_collateral: u128,
+        total_principal: u128,
+        corrected: bool,
+    },
+    BadDebtWrittenOff {
+        amount: u128,
+        remaining: u128,
+    },
+    ReferralPaid {
+        referrer: ActorId,
+        amount: u128,
+    },
+    LiquidityDeposited {
+        provider: ActorId,
+        amount: u128,
+    },
+    LiquidityWithdrawn {
+        provider: ActorId,
+        amount: u128,
+    },
+    OpenRolledBack {
+        borrower: ActorId,
+        collateral: u128,
+    },
+    LiquidationRolledBack {
+        loan_id: u64,
+        liquidator: ActorId,
+    },
+    ConsolidationRolledBack {
+        borrower: ActorId,
+        loan_ids: Vec<u64>,
+    },
+    RepayRolledBack {
+        loan_id: u64,
+        borrower: ActorId,
+    },
+    WithdrawalRolledBack {
+        loan_id: u64,
+        amount: u128,
+    },
+    TokensRescued {
+        token: ActorId,
+        to: ActorId,
+        amount: u128,
+    },
+    LiquidatorRewarded {
+        liquidator: ActorId,
+        amount: u128,
+    },
+    PermissionedLiquidationSet(bool),
+    LiquidatorAdded(ActorId),
+    LiquidatorRemoved(ActorId),
+    TokensVerified {
+        collateral_decimals: u8,
+        debt_decimals: u8,
+    },
+    DecimalsInfo {
+        collateral: u8,
+        debt: u8,
+    },
+    TokensUpdated {
+        collateral_token: ActorId,
+        debt_token: ActorId,
+    },
+    PartiallyLiquidated {
+        loan_id: u64,
+        repaid: u128,
+        seized: u128,
+    },
+    EmergencyCollateralReleased {
+        loan_id: u64,
+        borrower: ActorId,
+        amount: u128,
+    },
+    EmergencyReleaseProgress {
+        released: u32,
+        remaining: u32,
+    },
+    RoundingReserveSynced {
+        reserve: u128,
+        unabsorbed: u128, // surplus above MAX_ROUNDING_RESERVE, or a deficit the reserve could not cover
+    },
+    InterestCapitalized {
+        loan_id: u64,
+        amount: u128,
+        principal: u128,
+    },
+    LoanRebased {
+        loan_id: u64,
+        interest_rate: u128,
+        rate_mode: RateMode,
+    },
+    LoansRebased {
+        rebased: u32,
+        skipped: u32,
+    },
+    PausedSet {
+        paused: bool,
+        reason: Option<Vec<u8>>,
+    },
+    LiquidationSurplusCredited {
+        loan_id: u64,
+        borrower: ActorId,
+        amount: u128,
+    },
+    LiquidationSurplusClaimed {
+        borrower: ActorId,
+        amount: u128,
+    },
+    CollateralPayoutDeferred {
+        to: ActorId,
+        amount: u128,
+    },
+    CollateralAdded {
+        loan_id: u64,
+        amount: u128,
+    },
+    CollateralWithdrawn {
+        loan_id: u64,
+        amount: u128,
+    },
+    PartialRepaid {
+        loan_id: u64,
+        amount: u128,
+        remaining_principal: u128,
+    },
+    ProtectionFunded {
+        loan_id: u64,
+        amount: u128,
+    },
+    ProtectionApplied {
+        loan_id: u64,
+        amount: u128,
+    },
+    LoansConsolidated {
+        borrower: ActorId,
+        loan_ids: Vec<u64>,
+        repaid: u128,
+    },
+    CallProfile {
+        op: String,
+        gas_before: u64,
+        gas_after: u64,
+    },
+    SessionUsed {
+        account: ActorId,
+        key: ActorId,
+        action: ActionsForSession,
+    },
 }
 
 #[derive(Debug, Encode, Decode, TypeInfo, Clone)]
@@ -129,6 +477,236 @@ pub struct IoLendingState {
     pub user_loans: Vec<(ActorId, Vec<u64>)>,
     pub total_collateral: u128,
     pub total_principal: u128,
+    pub interest_free_period_ms: u64,
+    pub migration_mode: bool,
+    pub max_loans: u32,
+    pub min_open_interval_ms: u64,
+    pub bad_debt: u128,
+    pub origination_fee: u128,
+    pub referral_fee: u128,
+    pub protocol_reserve: u128,
+    pub price_oracle: Option<ActorId>,
+    pub total_liquidity: u128,
+    pub lp_balances: Vec<(ActorId, u128)>,
+    pub reserve_factor: u128,
+    pub cumulative_revenue: u128,
+    pub min_liquidation_principal: u128,
+    pub liquidation_surplus: Vec<(ActorId, u128)>,
+}
+
+/// Contract parameters, as returned by `query_config`
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct LendingConfig {
+    pub config_version: u16,
+    pub owner: ActorId,
+    pub collateral_token: ActorId,
+    pub debt_token: ActorId,
+    pub price_oracle: Option<ActorId>,
+    pub base_interest_rate: u128,
+    pub min_loan: u128,
+    pub max_loan: u128,
+    pub max_loans: u32,
+    pub interest_free_period_ms: u64,
+    pub min_open_interval_ms: u64,
+    pub origination_fee: u128,
+    pub referral_fee: u128,
+    pub reserve_factor: u128,
+    pub min_liquidation_principal: u128,
+    pub collateral_factor: u128,
+    pub liquidator_reward: u128,
+    pub daily_reward_cap: u128,
+    pub permissioned_liquidation: bool,
+    pub collateral_decimals: u8,
+    pub debt_decimals: u8,
+    pub tokens_verified: bool,
+    pub max_collateral_ratio: u128,
+    pub min_interest_rate: u128,
+    pub max_interest_rate: u128,
+    pub max_session_lifetime_ms: u64,
+    pub close_factor: u128,
+    pub liquidation_bonus: u128,
+    pub max_liquidations_per_window: u32,
+    pub liquidation_window_ms: u64,
+    pub accrual_start_delay_ms: u64,
+    pub loan_term_ms: u64,
+    pub max_fee_ratio: u128,
+    pub auto_compound_reserve: bool,
+    pub max_liquidation_bonus_abs: u128,
+    pub call_gas_limit: u64,
+    pub collateral_strategy: Option<ActorId>,
+    pub strategy_enabled: bool,
+    pub collateral_withdraw_cooldown_ms: u64,
+    pub min_collateral_topup: u128,
+    pub min_health_improvement: u128,
+    pub paused: bool,
+    pub pause_reason: Option<Vec<u8>>,
+    pub max_utilization_for_borrow: u128,
+    pub max_interest_multiple: u128,
+    pub liquidation_mode: LiquidationMode,
+    pub post_maturity_grace_ms: u64,
+    pub min_collateral_ratio: u128,
+    pub max_ltv: u128,
+    pub interest_capitalization: bool,
+    pub action_allowlist: Option<Vec<ActionsForSession>>,
+    pub liquidity_pool_enabled: bool,
+    pub migration_mode: bool,
+    pub max_price_age_ms: u64,
+}
+
+/// Commands with external calls, for `query_estimated_gas`
+#[derive(Debug, Clone, Copy, Encode, Decode, TypeInfo, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub enum OpKind {
+    OpenLoan,
+    Repay,
+    RepayWithCollateral,
+    ConsolidateAndRepay,
+    FundProtection,
+    Liquidate,
+    LiquidateAmount,
+    DepositLiquidity,
+    WithdrawLiquidity,
+    AddCollateral,
+    WithdrawCollateral,
+}
+
+impl OpKind {
+    /// Worst-case (token and strategy calls, oracle calls) made by the command.
+    fn external_calls(self) -> (u64, u64) {
+        match self {
+            // Collateral in, disbursal, referral payout, strategy deploy
+            OpKind::OpenLoan => (4, 1),
+            // Repayment in, burn, strategy recall, collateral out
+            OpKind::Repay | OpKind::ConsolidateAndRepay => (4, 0),
+            // Strategy recall, collateral to the owner and to the borrower
+            OpKind::RepayWithCollateral => (3, 1),
+            // Collateral in, returned if the loan closed meanwhile
+            OpKind::FundProtection => (2, 0),
+            // In Restore mode `liquidate` runs a partial liquidation, the costlier of the two
+            // (Seize: strategy recall, reward, collateral to the owner)
+            OpKind::Liquidate | OpKind::LiquidateAmount => (5, 1),
+            OpKind::DepositLiquidity | OpKind::WithdrawLiquidity => (1, 0),
+            // Collateral in, then a strategy deploy (or the refund if the loan closed meanwhile)
+            OpKind::AddCollateral => (2, 0),
+            // Strategy recall, collateral out
+            OpKind::WithdrawCollateral => (2, 1),
+        }
+    }
+}
+
+/// External contracts the service depends on, as returned by `query_integrations`
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct Integrations {
+    pub collateral_token: ActorId,
+    pub debt_token: ActorId,
+    pub price_oracle: Option<ActorId>,
+    pub collateral_decimals: u8,
+    pub debt_decimals: u8,
+    pub tokens_verified: bool, // decimals are only meaningful once `verify_tokens` ran
+}
+
+/// Protocol-wide figures, as returned inside `query_dashboard`
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct LendingStats {
+    pub total_collateral: u128,
+    pub total_principal: u128,
+    pub available_liquidity: u128,
+    pub protocol_reserve: u128,
+    pub bad_debt: u128,
+    pub cumulative_revenue: u128,
+    pub solvency: u128,
+    pub next_loan_id: u64,
+}
+
+/// Everything a front-end needs on load, as returned by `query_dashboard`
+#[derive(Debug, Encode, Decode, TypeInfo, Clone)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct Dashboard {
+    pub config: LendingConfig,
+    pub stats: LendingStats,
+    pub loans: Vec<(u64, Loan)>,
+}
+
+/// Aggregate exposure of a borrower over their active loans, as returned by `query_position`
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct Position {
+    pub active_loans: u32,
+    pub total_collateral: u128,
+    pub total_principal: u128,
+    pub total_owed_now: u128,
+    pub weighted_avg_rate: u128,      // principal-weighted, in DECIMALS_FACTOR
+    pub worst_health_factor: u128,    // lowest collateral ratio; u128::MAX without active loans
+    pub weighted_health_factor: u128, // collateral-weighted; u128::MAX without active loans
+}
+
+/// A borrower's active loans aggregated for a dashboard, as returned by `query_user_position`
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub struct UserPosition {
+    pub total_collateral: u128,
+    pub total_principal: u128,
+    pub total_interest_owed: u128,    // accrued to now, over every active loan
+    pub weighted_health_factor: u128, // collateral-weighted; u128::MAX without active loans
+    pub active_loans: u32,
+}
+
+/// Why a loan can or cannot be liquidated right now, as returned by `query_liquidation_status`
+#[derive(Debug, Encode, Decode, TypeInfo, Clone, Copy, PartialEq, Eq)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub enum LiquidationStatus {
+    NotActive,          // unknown loan id, or already repaid/liquidated
+    Safe,
+    Matured { since: u64 }, // past its term but healthy, within `post_maturity_grace_ms`
+    TooSmall,           // underwater or overdue, but below `min_liquidation_principal`
+    Liquidatable,
+}
+
+/// Errors reported by the service. Commands panic with the variant name
+/// so clients can match on the failure reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = sails_rs::scale_codec)]
+#[scale_info(crate = sails_rs::scale_info)]
+pub enum LendingError {
+    TooFrequent,
+    SelfReferral,
+    OracleUnavailable,
+    InsufficientLiquidity,
+    LoanNotFound,
+    AlreadyClosed,
+    AlreadyLiquidated,
+    LoanTooSmall,
+    NotInitialized,
+    NotLiquidator,
+    OverCollateralized,
+    RateOutOfBounds,
+    InvalidLoanBounds,
+    InvalidRepayAmount,
+    LiquidationThrottled,
+    TransferRejected,
+    InvalidActor,
+    FeeTooHigh,
+    StaleNonce,
+    WithdrawCooldown,
+    AmountTooSmall,
+    Paused,
+    PauseReasonTooLong,
+    UtilizationTooHigh,
+    StateTooLarge,
+    ActionDisabled,
+    PoolDisabled,
+    StalePrice,
 }
 
 // ---- Session/Signless actions ----
@@ -141,6 +719,39 @@ pub enum ActionsForSession {
     RepayLoan,
     LiquidateLoan,
     UpdateParams,
+    ManageLiquidity,
+}
+
+/// Checks that `session` may be used for `action` at `now`: the action is allowed for
+/// sessions at all, and the session is unexpired, within `max_session_lifetime_ms` and grants
+/// the action. Shared by `get_actor` and `query_session_allows`; the session key is checked
+/// by `get_actor` only, since it depends on the sender.
+fn usable_session<'a>(
+    state: &LendingState,
+    session: Option<&'a SessionData>,
+    action: ActionsForSession,
+    now: u64,
+) -> Result<&'a SessionData, String> {
+    // Actions the owner switched off for sessions; direct calls are never restricted,
+    // so borrowers can always repay and the owner can always lift the restriction
+    if let Some(allowlist) = &state.action_allowlist {
+        if !allowlist.contains(&action) {
+            return Err(format!("{:?}", LendingError::ActionDisabled));
+        }
+    }
+    let session = session.ok_or("No valid session for this account")?;
+    if session.expires <= now {
+        return Err("Session expired".into());
+    }
+    // Sessions valid further out than the allowed lifetime are treated as invalid
+    let max_lifetime = state.max_session_lifetime_ms;
+    if max_lifetime > 0 && session.expires > now.saturating_add(max_lifetime) {
+        return Err("Session lifetime too long".into());
+    }
+    if !session.allowed_actions.contains(&action) {
+        return Err("Action not allowed".into());
+    }
+    Ok(session)
 }
 
 fn get_actor(
@@ -149,19 +760,15 @@ fn get_actor(
     session_for_account: &Option<ActorId>,
     action: ActionsForSession,
 ) -> ActorId {
-    match session_for_account {
+    let actor = match session_for_account {
         Some(account) => {
-            let session = session_map
-                .get(account)
-                .expect("No valid session for this account");
-            assert!(
-                session.expires > exec::block_timestamp(),
-                "Session expired"
-            );
-            assert!(
-                session.allowed_actions.contains(&action),
-                "Action not allowed"
-            );
+            let session = usable_session(
+                LendingState::state_ref(),
+                session_map.get(account),
+                action,
+                exec::block_timestamp(),
+            )
+            .unwrap_or_else(|reason| panic!("{}", reason));
             assert_eq!(
                 session.key,
                 *msg_source,
@@ -170,307 +777,3678 @@ fn get_actor(
             *account
         }
         None => *msg_source,
+    };
+    // Every command acts on behalf of the resolved actor; zero would own loans and funds nobody controls
+    if actor == ActorId::zero() {
+        panic!("{:?}", LendingError::InvalidActor);
     }
+    actor
 }
 
-// ---- Io conversion ----
+// ---- Token amounts ----
 
-impl From<LendingState> for IoLendingState {
-    fn from(state: LendingState) -> Self {
-        IoLendingState {
-            owner: state.owner,
-            collateral_token: state.collateral_token,
-            debt_token: state.debt_token,
-            base_interest_rate: state.base_interest_rate,
-            min_loan: state.min_loan,
-            max_loan: state.max_loan,
-            loans: state.loans.iter().map(|(&id, loan)| (id, loan.clone())).collect(),
-            user_loans: state.user_loans.iter().map(|(&id, v)| (id, v.clone())).collect(),
-            total_collateral: state.total_collateral,
-            total_principal: state.total_principal,
-        }
+/// Converts an internal `u128` amount into the `U256` used by token calls.
+/// Widening is lossless, so every `u128` (including `u128::MAX`) round-trips.
+fn to_token_amount(v: u128) -> U256 {
+    U256::from(v)
+}
+
+/// Converts a whole-token amount into smallest units for a token with `decimals`,
+/// `None` on overflow.
+fn whole_to_raw(amount: u128, decimals: u8) -> Option<u128> {
+    10u128.checked_pow(decimals as u32)?.checked_mul(amount)
+}
+
+// ---- Token balances ----
+
+/// Balance of `account` on `token`, saturated to `u128`.
+async fn token_balance(token: ActorId, account: ActorId, gas_limit: u64) -> u128 {
+    let balance_of = ActionIo::BalanceOf(account).encode();
+    let reply = msg::send_bytes_with_gas_for_reply(token, balance_of, gas_limit, 0, 0)
+        .expect("Balance query failed")
+        .await
+        .expect("No reply for balance query");
+    let balance = U256::decode(&mut reply.as_slice()).expect("Invalid balance reply");
+    if balance > U256::from(u128::MAX) { u128::MAX } else { balance.as_u128() }
+}
+
+/// Decimals reported by `token`. Panics if the token does not reply in time or the
+/// reply is not a valid decimals value, i.e. it does not behave like a VFT.
+async fn token_decimals(token: ActorId, gas_limit: u64) -> u8 {
+    let decimals = ActionIo::Decimals.encode();
+    let reply = msg::send_bytes_with_gas_for_reply(token, decimals, gas_limit, 0, 0)
+        .and_then(|future| future.up_to(Some(REPLY_TIMEOUT_BLOCKS)))
+        .expect("Decimals query failed")
+        .await
+        .expect("Token did not respond");
+    u8::decode(&mut reply.as_slice()).expect("Invalid decimals reply")
+}
+
+/// Checks a token transfer/burn reply: the token answers `true` on success, anything
+/// else (including an undecodable payload) means it rejected the operation.
+fn expect_token_ok(reply: Vec<u8>) -> Result<(), LendingError> {
+    match bool::decode(&mut reply.as_slice()) {
+        Ok(true) => Ok(()),
+        _ => Err(LendingError::TransferRejected),
     }
 }
 
-// ---- Main Service ----
+// ---- Collateral ----
 
-#[derive(Debug, Clone, Default)] // Auditor: Ensure all necessary traits are derived
-pub struct Service; // Auditor: Ensure main service struct is public
+/// Sends `amount` of collateral held by the contract to `to`.
+async fn return_collateral(collateral_token: ActorId, to: ActorId, amount: u128, gas_limit: u64) {
+    let transfer = ActionIo::Transfer(to, to_token_amount(amount)).encode();
+    let reply = msg::send_bytes_with_gas_for_reply(collateral_token, transfer, gas_limit, 0, 0)
+        .expect("Collateral transfer failed")
+        .await
+        .expect("No reply collateral transfer");
+    expect_token_ok(reply).unwrap_or_else(|e| panic!("{:?}", e));
+}
 
-impl Service {
-    /// Initialize the lending contract. Owner is the origin of call.
-    pub fn seed(
-        collateral_token: ActorId,
-        debt_token: ActorId,
-        base_interest_rate: u128,
-        min_loan: u128,
-        max_loan: u128,
-    ) {
-        if collateral_token == ActorId::zero() || debt_token == ActorId::zero() {
-            panic!("Token addresses cannot be zero");
-        }
-        if min_loan == 0 || max_loan == 0 || max_loan < min_loan {
-            panic!("Loan thresholds invalid");
-        }
-        LendingState::init(
-            msg::source(),
-            collateral_token,
-            debt_token,
-            base_interest_rate,
-            min_loan,
-            max_loan,
-        );
+/// Sends `payload` to `token` and tells whether it accepted it, without panicking.
+async fn try_token_call(token: ActorId, payload: Vec<u8>, gas_limit: u64) -> bool {
+    match msg::send_bytes_with_gas_for_reply(token, payload, gas_limit, 0, 0) {
+        Ok(future) => future.await.is_ok_and(|reply| expect_token_ok(reply).is_ok()),
+        Err(_) => false,
     }
 }
 
-#[service(events = LendingEvent)]
-impl Service {
-    pub fn new() -> Self { Self }
+/// Books the protocol's share of repaid interest, already received by `take_repayment`.
+/// With auto-compounding it becomes lendable liquidity instead of idle reserve.
+fn book_reserve_share(state: &mut LendingState, reserve_share: u128) {
+    if state.auto_compound_reserve {
+        state.total_liquidity = state.total_liquidity.saturating_add(reserve_share);
+        state.compounded_reserve = state.compounded_reserve.saturating_add(reserve_share);
+    } else {
+        state.protocol_reserve = state.protocol_reserve.saturating_add(reserve_share);
+    }
+    state.cumulative_revenue = state.cumulative_revenue.saturating_add(reserve_share);
+}
 
-    /// Open a new loan. The caller must be the borrower authorized by session (or self if not signless).
-    pub async fn open_loan(
-        &mut self,
-        collateral: u128,
-        principal: u128,
-        session_for_account: Option<ActorId>
-    ) -> LendingEvent {
-        let msg_src = msg::source();
+/// Credits `amount` of collateral to `to`, to be taken out with `claim_liquidation_surplus`.
+fn credit_claimable(state: &mut LendingState, to: ActorId, amount: u128) {
+    let credited = state.liquidation_surplus.entry(to).or_default();
+    *credited = credited.saturating_add(amount);
+    state.total_liquidation_surplus = state.total_liquidation_surplus.saturating_add(amount);
+}
 
-        // Session-aware: If session_for_account is set, will use session verification
-        let sessions = Storage::get_session_map();
-        let borrower = get_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::OpenLoan);
+// ---- Collateral strategy ----
 
-        let mut state = LendingState::state_mut();
+/// Request understood by the collateral yield strategy. `Withdraw` asks it to transfer
+/// `amount` of collateral back to the caller; it replies `true` once done.
+#[derive(Encode)]
+#[codec(crate = sails_rs::scale_codec)]
+enum StrategyRequest {
+    Withdraw(u128),
+}
 
-        // Validate input
-        if principal < state.min_loan || principal > state.max_loan {
-            panic!("Loan principal out of bounds");
-        }
-        if collateral == 0 {
-            panic!("Must provide collateral");
-        }
-        // Check collateralization ratio
-        let ratio = collateral
-            .saturating_mul(DECIMALS_FACTOR)
-            .checked_div(principal)
-            .expect("Division error");
-        if ratio < MIN_COLLATERAL_RATIO {
-            panic!("Insufficient collateral ratio");
-        }
+// ---- Liquidity ----
 
-        if state.loans.len() >= 10_000 {
-            panic!("Loan limit reached"); 
-        }
+/// Debt tokens provided by LPs that are not lent out. The protocol reserve is held
+/// on top of LP liquidity, so it is never counted as lendable.
+fn available_liquidity(state: &LendingState) -> u128 {
+    state.total_liquidity.saturating_sub(state.total_principal)
+}
 
-        // Transfer collateral from user to contract
-        let transfer_from = ActionIo::TransferFrom(borrower, exec::program_id(), collateral.into()).encode();
-        msg::send_bytes_with_gas_for_reply(state.collateral_token, transfer_from, 5_000_000_000, 0, 0)
-            .expect("Collateral transfer failed")
-            .await
-            .expect("No reply for collateral transfer");
+/// Share of LP liquidity lent out, `total_principal / total_liquidity` in DECIMALS_FACTOR,
+/// capped at 100% (0 without liquidity).
+fn utilization(state: &LendingState) -> u128 {
+    if state.total_liquidity == 0 {
+        return 0;
+    }
+    mul_div(state.total_principal, DECIMALS_FACTOR, state.total_liquidity).min(DECIMALS_FACTOR)
+}
 
-        // Mint debt tokens to user (simulate FT transfer)
-        let mint_debt = ActionIo::TransferFrom(exec::program_id(), borrower, principal.into()).encode();
-        msg::send_bytes_with_gas_for_reply(state.debt_token, mint_debt, 5_000_000_000, 0, 0)
-            .expect("Debt token transfer failed")
-            .await
-            .expect("No reply for debt minting");
+/// In pool mode, writes principal that leaves `total_principal` without being paid back in
+/// debt tokens (seized, repaid in collateral or released) off LP liquidity, so that it is not
+/// counted as lendable again.
+fn write_off_pool_principal(state: &mut LendingState, principal: u128) {
+    if state.liquidity_pool_enabled {
+        state.total_liquidity = state.total_liquidity.saturating_sub(principal);
+    }
+}
 
-        let loan_id = state.next_loan_id;
-        let block = exec::block_timestamp() as u64; 
+// ---- Price oracle ----
 
-        let loan = Loan {
-            borrower,
-            collateral,
-            principal,
-            interest_rate: state.base_interest_rate,
-            start_block: block,
-            status: LoanStatus::Active,
-        };
-        state.loans.insert(loan_id, loan);
-        let user_loans = state.user_loans.entry(borrower).or_default();
-        if user_loans.len() >= 100 {
-            panic!("User loan limit reached"); 
-        }
-        user_loans.push(loan_id);
-        state.next_loan_id = state.next_loan_id.checked_add(1).expect("Loan id overflow"); 
-        state.total_collateral = state.total_collateral.checked_add(collateral).expect("Collateral overflow"); 
-        state.total_principal = state.total_principal.checked_add(principal).expect("Principal overflow"); 
+/// Request understood by the price oracle: price of `token` in debt token units, in DECIMALS_FACTOR.
+#[derive(Encode)]
+#[codec(crate = sails_rs::scale_codec)]
+enum OracleRequest {
+    GetPrice(ActorId),
+}
 
+/// Price of `token` relative to the debt token, in DECIMALS_FACTOR. Without an oracle the
+/// tokens are valued 1:1. The oracle call uses bounded gas and a reply timeout; on an error
+/// or missing reply the last cached price is used if it is at most `max_price_age_ms` old,
+/// otherwise the operation is refused.
+async fn fetch_price(state: &mut LendingState, token: ActorId) -> Result<u128, LendingError> {
+    let Some(oracle) = state.price_oracle else {
+        return Ok(DECIMALS_FACTOR);
+    };
+    let request = OracleRequest::GetPrice(token).encode();
+    let reply = match msg::send_bytes_with_gas_for_reply(oracle, request, ORACLE_GAS_LIMIT, 0, 0)
+        .and_then(|future| future.up_to(Some(REPLY_TIMEOUT_BLOCKS)))
+    {
+        Ok(future) => future.await.ok(),
+        Err(_) => None,
+    };
+    match reply.and_then(|bytes| u128::decode(&mut bytes.as_slice()).ok()) {
+        Some(price) if price > 0 => {
+            state.cached_prices.insert(token, (price, exec::block_timestamp()));
+            Ok(price)
+        }
+        _ => cached_price(state, token, exec::block_timestamp()),
+    }
+}
+
+/// Cached price of `token` if it was fetched at most `max_price_age_ms` before `now`.
+fn cached_price(state: &LendingState, token: ActorId, now: u64) -> Result<u128, LendingError> {
+    let &(price, fetched_at) = state.cached_prices.get(&token).ok_or(LendingError::OracleUnavailable)?;
+    if state.max_price_age_ms == 0 || now.saturating_sub(fetched_at) > state.max_price_age_ms {
+        return Err(LendingError::StalePrice);
+    }
+    Ok(price)
+}
+
+// ---- Loan lookup ----
+
+/// Active loan by id; fails with `LoanNotFound`, `AlreadyClosed` or `AlreadyLiquidated`.
+fn active_loan(loans: &mut SailsHashMap<u64, Loan>, loan_id: u64) -> &mut Loan {
+    let loan = loans
+        .get_mut(&loan_id)
+        .unwrap_or_else(|| panic!("{:?}", LendingError::LoanNotFound));
+    match loan.status {
+        LoanStatus::Active => loan,
+        LoanStatus::Closed => panic!("{:?}", LendingError::AlreadyClosed),
+        LoanStatus::Liquidated => panic!("{:?}", LendingError::AlreadyLiquidated),
+    }
+}
+
+/// Moves the loan's protection deposit into its collateral and returns the amount moved.
+/// The deposit is already counted in `total_collateral`, so totals are unchanged.
+fn apply_protection(loan: &mut Loan) -> u128 {
+    let amount = loan.protection_deposit;
+    loan.collateral = loan.collateral.saturating_add(amount);
+    loan.protection_deposit = 0;
+    amount
+}
+
+/// Drops a closed loan from its borrower's index so `user_loans` only lists open loans.
+/// The loan itself stays in `loans` for history.
+fn unindex_loan(user_loans: &mut SailsHashMap<ActorId, Vec<u64>>, borrower: ActorId, loan_id: u64) {
+    if let Some(ids) = user_loans.get_mut(&borrower) {
+        if let Some(pos) = ids.iter().position(|id| *id == loan_id) {
+            // Order-preserving so positional pages over a user's loans stay stable
+            ids.remove(pos);
+        }
+    }
+}
+
+/// Puts a loan back into its borrower's index (e.g. when a closure is rolled back), in id
+/// order and at most once.
+fn reindex_loan(user_loans: &mut SailsHashMap<ActorId, Vec<u64>>, borrower: ActorId, loan_id: u64) {
+    let ids = user_loans.entry(borrower).or_default();
+    let pos = ids.partition_point(|id| *id < loan_id);
+    if ids.get(pos) != Some(&loan_id) {
+        ids.insert(pos, loan_id);
+    }
+}
+
+// ---- Health ----
+
+/// Last price known for `token` without calling the oracle: 1:1 when no oracle is set,
+/// otherwise the cached price whatever its age (1:1 until the oracle has been queried once).
+fn last_known_price(state: &LendingState, token: ActorId) -> u128 {
+    match state.price_oracle {
+        None => DECIMALS_FACTOR,
+        Some(_) => state.cached_prices.get(&token).map_or(DECIMALS_FACTOR, |&(price, _)| price),
+    }
+}
+
+/// Collateral-to-principal ratio in DECIMALS_FACTOR.
+fn collateral_ratio(collateral: u128, principal: u128) -> u128 {
+    mul_div(collateral, DECIMALS_FACTOR, principal.max(1))
+}
+
+/// Value of `collateral` in debt token units at `price` (in DECIMALS_FACTOR).
+fn collateral_value(collateral: u128, price: u128) -> u128 {
+    mul_div(collateral, price, DECIMALS_FACTOR)
+}
+
+/// Part of the collateral value that counts towards borrowing: `value * collateral_factor`.
+fn borrowing_power(collateral: u128, price: u128, collateral_factor: u128) -> u128 {
+    mul_div(collateral_value(collateral, price), collateral_factor, DECIMALS_FACTOR)
+}
+
+/// Loan-to-value limit matching `min_ratio`, `1 / min_ratio` in DECIMALS_FACTOR. Derived rather
+/// than stored; rounding up makes it read back exactly the LTV given to `set_max_ltv`.
+fn max_ltv(min_ratio: u128) -> u128 {
+    mul_div_up(DECIMALS_FACTOR, DECIMALS_FACTOR, min_ratio.max(1))
+}
+
+/// Minimum collateral ratio matching a loan-to-value limit, `1 / ltv` in DECIMALS_FACTOR,
+/// rounded up so the limit is never looser than the LTV asked for.
+fn min_ratio_for_ltv(ltv: u128) -> u128 {
+    mul_div_up(DECIMALS_FACTOR, DECIMALS_FACTOR, ltv.max(1))
+}
+
+/// Stores `min_ratio`, keeping it below `max_collateral_ratio`.
+fn set_collateral_limit(state: &mut LendingState, min_ratio: u128) {
+    if min_ratio <= DECIMALS_FACTOR {
+        panic!("Min collateral ratio must exceed 100%");
+    }
+    if state.max_collateral_ratio != 0 && min_ratio >= state.max_collateral_ratio {
+        panic!("Min collateral ratio must stay below max ratio");
+    }
+    state.min_collateral_ratio = min_ratio;
+}
+
+/// Whether a term loan is past maturity plus `grace_ms`, and so liquidatable whatever its health.
+fn is_overdue(loan: &Loan, now: u64, grace_ms: u64) -> bool {
+    loan.maturity_ts.is_some_and(|maturity| now >= maturity.saturating_add(grace_ms))
+}
+
+/// Debt a liquidator has to repay so that, once the matching collateral plus `bonus` is seized,
+/// the loan sits at `min_ratio` again. Accrued interest is repaid first and does not
+/// improve health, so it is added on top of the principal part `r` solving
+/// `(collateral - r * (1 + bonus) / price) * price * collateral_factor = min_ratio * (principal - r)`.
+/// `None` if seizing at this bonus can never restore the loan.
+fn restore_repay_amount(loan: &Loan, price: u128, collateral_factor: u128, bonus: u128, min_ratio: u128) -> Option<u128> {
+    let seize_factor = mul_div(DECIMALS_FACTOR.saturating_add(bonus), collateral_factor, DECIMALS_FACTOR);
+    if seize_factor >= min_ratio {
+        return None;
+    }
+    let interest_seized = mul_div_up(
+        mul_div_up(loan.accrued_so_far, DECIMALS_FACTOR, price.max(1)),
+        DECIMALS_FACTOR.saturating_add(bonus),
+        DECIMALS_FACTOR,
+    );
+    let collateral = loan.collateral.saturating_sub(interest_seized);
+    let target = mul_div_up(loan.principal, min_ratio, DECIMALS_FACTOR);
+    let shortfall = target.saturating_sub(borrowing_power(collateral, price, collateral_factor));
+    let from_principal = mul_div_up(shortfall, DECIMALS_FACTOR, min_ratio - seize_factor);
+    Some(loan.accrued_so_far.saturating_add(from_principal))
+}
+
+/// Health of a position: borrowing power over principal, in DECIMALS_FACTOR.
+/// Below `min_collateral_ratio` the position is liquidatable.
+fn health_factor(collateral: u128, principal: u128, price: u128, collateral_factor: u128) -> u128 {
+    collateral_ratio(borrowing_power(collateral, price, collateral_factor), principal)
+}
+
+/// Collateral price at which `loan`'s health factor falls to `min_ratio`; None when no price
+/// can make it liquidatable that way (no collateral, or a zero collateral factor).
+fn liquidation_price(loan: &Loan, min_ratio: u128, collateral_factor: u128) -> Option<u128> {
+    if loan.collateral == 0 || collateral_factor == 0 {
+        return None;
+    }
+    let price = mul_div(loan.principal, min_ratio, loan.collateral);
+    Some(mul_div(price, DECIMALS_FACTOR, collateral_factor))
+}
+
+// ---- Interest ----
+
+/// Interest accrued by `loan` between `last_accrual_ts` and `now` (not yet in `accrued_so_far`).
+/// The first `interest_free_period_ms` milliseconds after the loan start are not charged.
+/// With a nonzero `max_interest_multiple` the loan's total interest, capitalized or not, never
+/// exceeds `lent_principal * max_interest_multiple / DECIMALS_FACTOR`. Floating loans follow each rate
+/// change recorded in `floating_checkpoints` (see `checkpoint_floating_rate`).
+fn compute_interest(
+    loan: &Loan,
+    now: u64,
+    interest_free_period_ms: u64,
+    max_interest_multiple: u128,
+    floating_checkpoints: &[(u64, u128, u128)],
+) -> u128 {
+    let accrual_start = loan
+        .last_accrual_ts
+        .max(loan.start_timestamp.saturating_add(interest_free_period_ms));
+    // Exact products with a single rounding step, always down (in the borrower's favor)
+    let interest = match loan.rate_mode {
+        RateMode::Fixed => {
+            let duration = now.saturating_sub(accrual_start) as u128;
+            mul3_div(loan.principal, loan.interest_rate, duration, YEAR_MS * DECIMALS_FACTOR)
+        }
+        RateMode::Floating => {
+            let rate_ms = floating_rate_integral(floating_checkpoints, loan.interest_rate, accrual_start, now);
+            mul_div(loan.principal, rate_ms, YEAR_MS * DECIMALS_FACTOR)
+        }
+    };
+    if max_interest_multiple == 0 {
+        return interest;
+    }
+    let cap = mul_div(lent_principal(loan), max_interest_multiple, DECIMALS_FACTOR);
+    interest.min(cap.saturating_sub(settled_interest(loan)))
+}
+
+/// Annual `rate` compounded `periods` times a year, `(1 + rate / periods)^periods - 1`, in
+/// DECIMALS_FACTOR. Rounds down.
+fn compounded_rate(rate: u128, periods: u32) -> u128 {
+    let per_period = rate / u128::from(periods.max(1));
+    pow_fixed(DECIMALS_FACTOR.saturating_add(per_period), periods.max(1), DECIMALS_FACTOR)
+        .saturating_sub(DECIMALS_FACTOR)
+}
+
+/// Interest already charged to `loan`: accrued, plus what was capitalized into its principal.
+fn settled_interest(loan: &Loan) -> u128 {
+    loan.accrued_so_far.saturating_add(loan.capitalized_interest)
+}
+
+/// Principal actually lent out on `loan`, without the interest capitalized into it.
+fn lent_principal(loan: &Loan) -> u128 {
+    loan.principal.saturating_sub(loan.capitalized_interest)
+}
+
+/// Principal plus all interest owed on `loan` at `now`.
+fn total_owed(
+    loan: &Loan,
+    now: u64,
+    interest_free_period_ms: u64,
+    max_interest_multiple: u128,
+    floating_checkpoints: &[(u64, u128, u128)],
+) -> u128 {
+    loan.principal
+        .saturating_add(loan.accrued_so_far)
+        .saturating_add(compute_interest(loan, now, interest_free_period_ms, max_interest_multiple, floating_checkpoints))
+}
+
+/// Rate applied to new loans: the base rate clamped to the configured floor and ceiling.
+fn current_borrow_rate(state: &LendingState) -> u128 {
+    let rate = state.base_interest_rate.max(state.min_interest_rate);
+    if state.max_interest_rate == 0 { rate } else { rate.min(state.max_interest_rate) }
+}
+
+/// Rate `loan` accrues at right now: its snapshot when fixed, the current borrow rate when floating.
+fn loan_rate(state: &LendingState, loan: &Loan) -> u128 {
+    match loan.rate_mode {
+        RateMode::Fixed => loan.interest_rate,
+        RateMode::Floating => current_borrow_rate(state),
+    }
+}
+
+/// Records a change of the current borrow rate at `now`, so floating loans accrue at
+/// `previous_rate` up to here and at the new rate after without each loan being touched.
+/// Called whenever the current borrow rate may have changed.
+fn checkpoint_floating_rate(state: &mut LendingState, previous_rate: u128, now: u64) {
+    let rate = current_borrow_rate(state);
+    if rate == previous_rate {
+        return;
+    }
+    let checkpoints = &mut state.floating_rate_checkpoints;
+    let index = if checkpoints.is_empty() { 0 } else { floating_rate_index(checkpoints, now) };
+    match checkpoints.last_mut() {
+        // Several changes in one block: only the last rate is ever charged
+        Some(last) if last.0 == now => last.2 = rate,
+        _ => checkpoints.push((now, index, rate)),
+    }
+    if checkpoints.len() > MAX_FLOATING_CHECKPOINTS {
+        compact_floating_checkpoints(checkpoints);
+    }
+}
+
+/// Merges the two oldest checkpoints into one charging their average rate up to the third.
+/// The index at the third checkpoint and after it is unchanged; only loans last accrued
+/// before it are charged the average instead of the exact rates.
+fn compact_floating_checkpoints(checkpoints: &mut Vec<(u64, u128, u128)>) {
+    if checkpoints.len() < 3 {
+        return;
+    }
+    let (first_at, first_index, _) = checkpoints[0];
+    let (third_at, third_index, _) = checkpoints[2];
+    checkpoints[0].2 = third_index.saturating_sub(first_index) / third_at.saturating_sub(first_at).max(1) as u128;
+    checkpoints.remove(1);
+}
+
+/// Sum of `rate * ms` for the floating rate from the first checkpoint up to `ts`, which must
+/// not be before the first checkpoint.
+fn floating_rate_index(checkpoints: &[(u64, u128, u128)], ts: u64) -> u128 {
+    let pos = checkpoints.partition_point(|(at, _, _)| *at <= ts);
+    let (at, index, rate) = checkpoints[pos.saturating_sub(1)];
+    index.saturating_add(rate.saturating_mul(ts.saturating_sub(at) as u128))
+}
+
+/// Sum of `rate * ms` charged to a floating loan over `[from, to)`. Before the first
+/// checkpoint the rate never changed, so it is the loan's `initial_rate` snapshot.
+fn floating_rate_integral(checkpoints: &[(u64, u128, u128)], initial_rate: u128, from: u64, to: u64) -> u128 {
+    if to <= from {
+        return 0;
+    }
+    let Some(&(first, _, _)) = checkpoints.first() else {
+        return initial_rate.saturating_mul((to - from) as u128);
+    };
+    let before = initial_rate.saturating_mul(to.min(first).saturating_sub(from) as u128);
+    let from = from.max(first);
+    if to <= from {
+        return before;
+    }
+    before.saturating_add(floating_rate_index(checkpoints, to).saturating_sub(floating_rate_index(checkpoints, from)))
+}
+
+/// `(total_collateral, total_principal)` recomputed from the active loans; protection deposits
+/// count as collateral since the contract holds them.
+fn recomputed_totals(state: &LendingState) -> (u128, u128) {
+    state
+        .loans
+        .values()
+        .filter(|loan| loan.status == LoanStatus::Active)
+        .fold((0u128, 0u128), |(collateral, principal), loan| {
+            (
+                collateral
+                    .saturating_add(loan.collateral)
+                    .saturating_add(loan.protection_deposit),
+                principal.saturating_add(loan.principal),
+            )
+        })
+}
+
+/// Counts a liquidation against the current window, starting a new window once the previous
+/// one has elapsed. Fails when the window's cap is already reached.
+fn throttle_liquidation(state: &mut LendingState, now: u64) -> Result<(), LendingError> {
+    if state.max_liquidations_per_window == 0 {
+        return Ok(());
+    }
+    if now >= state.liquidation_window_start.saturating_add(state.liquidation_window_ms) {
+        state.liquidation_window_start = now;
+        state.liquidations_in_window = 0;
+    }
+    if state.liquidations_in_window >= state.max_liquidations_per_window {
+        return Err(LendingError::LiquidationThrottled);
+    }
+    state.liquidations_in_window += 1;
+    Ok(())
+}
+
+/// Gives back the slot `throttle_liquidation` took for a liquidation that did not happen, if
+/// the window it was counted in (`window_start`) is still the current one.
+fn refund_liquidation_slot(state: &mut LendingState, window_start: u64) {
+    if state.max_liquidations_per_window > 0 && state.liquidation_window_start == window_start {
+        state.liquidations_in_window = state.liquidations_in_window.saturating_sub(1);
+    }
+}
+
+/// Gives back the loan slot `open_loan` holds for `borrower` while its transfers are pending.
+fn release_open_slot(state: &mut LendingState, borrower: ActorId) {
+    if let Some(pending) = state.pending_opens.get_mut(&borrower) {
+        *pending -= 1;
+        if *pending == 0 {
+            state.pending_opens.remove(&borrower);
+        }
+    }
+}
+
+/// Undoes what a rolled back `open_loan` reserved before its transfers: the loan slot, and the
+/// anti-spam timestamp (`now`) unless a later open replaced it.
+fn cancel_open(state: &mut LendingState, borrower: ActorId, now: u64, previous_open_ts: Option<u64>) {
+    release_open_slot(state, borrower);
+    if state.last_open_ts.get(&borrower) == Some(&now) {
+        match previous_open_ts {
+            Some(ts) => state.last_open_ts.insert(borrower, ts),
+            None => state.last_open_ts.remove(&borrower),
+        };
+    }
+}
+
+/// Consumes the owner's replay-protection nonce: `nonce` must equal the current `admin_nonce`,
+/// so a retried or duplicated admin call is rejected instead of applied twice.
+fn use_admin_nonce(state: &mut LendingState, nonce: u64) {
+    if nonce != state.admin_nonce {
+        panic!("{:?}", LendingError::StaleNonce);
+    }
+    state.admin_nonce += 1;
+}
+
+/// Checks a proposed `update_params` change against the current configuration.
+fn validate_params(
+    state: &LendingState,
+    new_rate: u128,
+    min_loan: u128,
+    max_loan: u128,
+) -> Result<(), LendingError> {
+    if min_loan == 0 || min_loan > max_loan {
+        return Err(LendingError::InvalidLoanBounds);
+    }
+    if new_rate < state.min_interest_rate
+        || (state.max_interest_rate != 0 && new_rate > state.max_interest_rate)
+    {
+        return Err(LendingError::RateOutOfBounds);
+    }
+    Ok(())
+}
+
+/// Rolls interest accrued up to `now` into `accrued_so_far` and advances `last_accrual_ts`.
+fn settle_accrual(
+    loan: &mut Loan,
+    now: u64,
+    interest_free_period_ms: u64,
+    max_interest_multiple: u128,
+    floating_checkpoints: &[(u64, u128, u128)],
+) {
+    let interest = compute_interest(loan, now, interest_free_period_ms, max_interest_multiple, floating_checkpoints);
+    loan.accrued_so_far = loan.accrued_so_far.saturating_add(interest);
+    loan.last_accrual_ts = loan.last_accrual_ts.max(now);
+    if loan.rate_mode == RateMode::Floating {
+        if let Some(&(_, _, rate)) = floating_checkpoints.last() {
+            loan.interest_rate = rate;
+        }
+    }
+}
+
+// ---- Io conversion ----
+
+impl From<&LendingState> for IoLendingState {
+    fn from(state: &LendingState) -> Self {
+        IoLendingState {
+            owner: state.owner,
+            collateral_token: state.collateral_token,
+            debt_token: state.debt_token,
+            base_interest_rate: state.base_interest_rate,
+            min_loan: state.min_loan,
+            max_loan: state.max_loan,
+            loans: state.loans.iter().map(|(&id, loan)| (id, loan.clone())).collect(),
+            user_loans: state.user_loans.iter().map(|(&id, v)| (id, v.clone())).collect(),
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+            interest_free_period_ms: state.interest_free_period_ms,
+            migration_mode: state.migration_mode,
+            max_loans: state.max_loans,
+            min_open_interval_ms: state.min_open_interval_ms,
+            bad_debt: state.bad_debt,
+            origination_fee: state.origination_fee,
+            referral_fee: state.referral_fee,
+            protocol_reserve: state.protocol_reserve,
+            price_oracle: state.price_oracle,
+            total_liquidity: state.total_liquidity,
+            lp_balances: state.lp_balances.iter().map(|(&id, &v)| (id, v)).collect(),
+            reserve_factor: state.reserve_factor,
+            cumulative_revenue: state.cumulative_revenue,
+            min_liquidation_principal: state.min_liquidation_principal,
+            liquidation_surplus: state.liquidation_surplus.iter().map(|(&id, &v)| (id, v)).collect(),
+        }
+    }
+}
+
+impl From<&LendingState> for LendingConfig {
+    fn from(state: &LendingState) -> Self {
+        LendingConfig {
+            config_version: state.config_version,
+            owner: state.owner,
+            collateral_token: state.collateral_token,
+            debt_token: state.debt_token,
+            price_oracle: state.price_oracle,
+            base_interest_rate: state.base_interest_rate,
+            min_loan: state.min_loan,
+            max_loan: state.max_loan,
+            max_loans: state.max_loans,
+            interest_free_period_ms: state.interest_free_period_ms,
+            min_open_interval_ms: state.min_open_interval_ms,
+            origination_fee: state.origination_fee,
+            referral_fee: state.referral_fee,
+            reserve_factor: state.reserve_factor,
+            min_liquidation_principal: state.min_liquidation_principal,
+            collateral_factor: state.collateral_factor,
+            liquidator_reward: state.liquidator_reward,
+            daily_reward_cap: state.daily_reward_cap,
+            permissioned_liquidation: state.permissioned_liquidation,
+            collateral_decimals: state.collateral_decimals,
+            debt_decimals: state.debt_decimals,
+            tokens_verified: state.tokens_verified,
+            max_collateral_ratio: state.max_collateral_ratio,
+            min_interest_rate: state.min_interest_rate,
+            max_interest_rate: state.max_interest_rate,
+            max_session_lifetime_ms: state.max_session_lifetime_ms,
+            close_factor: state.close_factor,
+            liquidation_bonus: state.liquidation_bonus,
+            max_liquidations_per_window: state.max_liquidations_per_window,
+            liquidation_window_ms: state.liquidation_window_ms,
+            accrual_start_delay_ms: state.accrual_start_delay_ms,
+            loan_term_ms: state.loan_term_ms,
+            max_fee_ratio: state.max_fee_ratio,
+            auto_compound_reserve: state.auto_compound_reserve,
+            max_liquidation_bonus_abs: state.max_liquidation_bonus_abs,
+            call_gas_limit: state.call_gas_limit,
+            collateral_strategy: state.collateral_strategy,
+            strategy_enabled: state.strategy_enabled,
+            collateral_withdraw_cooldown_ms: state.collateral_withdraw_cooldown_ms,
+            min_collateral_topup: state.min_collateral_topup,
+            min_health_improvement: state.min_health_improvement,
+            paused: state.paused,
+            pause_reason: state.pause_reason.clone(),
+            max_utilization_for_borrow: state.max_utilization_for_borrow,
+            max_interest_multiple: state.max_interest_multiple,
+            liquidation_mode: state.liquidation_mode,
+            post_maturity_grace_ms: state.post_maturity_grace_ms,
+            min_collateral_ratio: state.min_collateral_ratio,
+            max_ltv: max_ltv(state.min_collateral_ratio),
+            interest_capitalization: state.interest_capitalization,
+            action_allowlist: state.action_allowlist.clone(),
+            liquidity_pool_enabled: state.liquidity_pool_enabled,
+            migration_mode: state.migration_mode,
+            max_price_age_ms: state.max_price_age_ms,
+        }
+    }
+}
+
+// ---- Main Service ----
+
+#[derive(Debug, Clone, Default)] // Auditor: Ensure all necessary traits are derived
+pub struct Service; // Auditor: Ensure main service struct is public
+
+impl Service {
+    /// Initialize the lending contract. Owner is the origin of call.
+    /// `max_loans` defaults to 10,000 when not provided.
+    pub async fn seed(
+        collateral_token: ActorId,
+        debt_token: ActorId,
+        base_interest_rate: u128,
+        min_loan: u128,
+        max_loan: u128,
+        max_loans: Option<u32>,
+    ) {
+        if collateral_token == ActorId::zero() || debt_token == ActorId::zero() {
+            panic!("Token addresses cannot be zero");
+        }
+        if collateral_token == debt_token {
+            panic!("Collateral and debt token must differ");
+        }
+        if min_loan == 0 || max_loan == 0 || max_loan < min_loan {
+            panic!("Loan thresholds invalid");
+        }
+        // Amounts of the two tokens are compared without normalization, so decimals must match
+        let collateral_decimals = token_decimals(collateral_token, DEFAULT_CALL_GAS_LIMIT).await;
+        let debt_decimals = token_decimals(debt_token, DEFAULT_CALL_GAS_LIMIT).await;
+        if collateral_decimals != debt_decimals {
+            panic!(
+                "Token decimals differ: collateral {}, debt {}",
+                collateral_decimals, debt_decimals
+            );
+        }
+        LendingState::init(
+            msg::source(),
+            collateral_token,
+            debt_token,
+            base_interest_rate,
+            min_loan,
+            max_loan,
+            max_loans.unwrap_or(DEFAULT_MAX_LOANS),
+        );
+        let state = LendingState::state_mut();
+        state.collateral_decimals = collateral_decimals;
+        state.debt_decimals = debt_decimals;
+        state.tokens_verified = true;
+        // Lets indexers render the raw amounts of every later event
+        Service::new().emit_event(LendingEvent::DecimalsInfo {
+            collateral: collateral_decimals,
+            debt: debt_decimals,
+        }).expect("Event error");
+    }
+}
+
+#[service(events = LendingEvent)]
+impl Service {
+    pub fn new() -> Self { Self }
+
+    /// Emits a `CallProfile` for an external call that started with `gas_before` left.
+    fn profile(&mut self, profiling: bool, op: &str, gas_before: u64) {
+        if profiling {
+            self.emit_event(LendingEvent::CallProfile {
+                op: op.into(),
+                gas_before,
+                gas_after: exec::gas_available(),
+            }).expect("Event error");
+        }
+    }
+
+    /// Like `profile`, for the `call` made by `op`; the label is only built while profiling.
+    fn profile_call(&mut self, profiling: bool, op: &str, call: &str, gas_before: u64) {
+        if profiling {
+            self.profile(profiling, &format!("{op}.{call}"), gas_before);
+        }
+    }
+
+    /// Collects a repayment of `burn + keep` debt tokens from `payer`: `keep` stays in the
+    /// contract (principal backing LP liquidity again, and the protocol's share of the interest)
+    /// and `burn` is burned. The payer is charged in a single token call, so on error nothing
+    /// was taken. Returns the amount actually burned: once the payer has paid, a rejected burn of
+    /// the contract's own tokens leaves them in the contract (see `sync_rounding_reserve`) rather
+    /// than failing the repayment. Each token call is profiled under `op`.
+    async fn take_repayment(
+        &mut self,
+        state: &mut LendingState,
+        payer: ActorId,
+        burn: u128,
+        keep: u128,
+        op: &str,
+    ) -> Result<u128, LendingError> {
+        if keep == 0 {
+            if burn == 0 {
+                return Ok(0);
+            }
+            let burn_debt = ActionIo::Burn(payer, to_token_amount(burn)).encode();
+            let gas_before = exec::gas_available();
+            let burned = try_token_call(state.debt_token, burn_debt, state.call_gas_limit).await;
+            self.profile_call(state.profiling, op, "burn_debt", gas_before);
+            return if burned { Ok(burn) } else { Err(LendingError::TransferRejected) };
+        }
+        let transfer_from = ActionIo::TransferFrom(payer, exec::program_id(), to_token_amount(burn.saturating_add(keep))).encode();
+        let gas_before = exec::gas_available();
+        let taken = try_token_call(state.debt_token, transfer_from, state.call_gas_limit).await;
+        self.profile_call(state.profiling, op, "transfer_from", gas_before);
+        if !taken {
+            return Err(LendingError::TransferRejected);
+        }
+        if burn == 0 {
+            return Ok(0);
+        }
+        let burn_debt = ActionIo::Burn(exec::program_id(), to_token_amount(burn)).encode();
+        let gas_before = exec::gas_available();
+        let burned = try_token_call(state.debt_token, burn_debt, state.call_gas_limit).await;
+        self.profile_call(state.profiling, op, "burn_debt", gas_before);
+        Ok(if burned { burn } else { 0 })
+    }
+
+    /// Makes sure `amount` of collateral can be paid out of the contract by withdrawing up to
+    /// that much (bounded by what is deployed) from the strategy, and tells whether that worked.
+    /// Does not panic: most callers run it after state is committed, and a payout the contract
+    /// then cannot cover is credited as claimable collateral instead.
+    async fn recall_from_strategy(&mut self, state: &mut LendingState, amount: u128, op: &str) -> bool {
+        let Some(strategy) = state.collateral_strategy else { return true };
+        let recall = amount.min(state.collateral_in_strategy);
+        if recall == 0 {
+            return true;
+        }
+        let withdraw = StrategyRequest::Withdraw(recall).encode();
+        let gas_before = exec::gas_available();
+        let recalled = try_token_call(strategy, withdraw, state.call_gas_limit).await;
+        self.profile_call(state.profiling, op, "strategy_withdraw", gas_before);
+        if recalled {
+            state.collateral_in_strategy = state.collateral_in_strategy.saturating_sub(recall);
+        }
+        recalled
+    }
+
+    /// Sends `amount` of newly received collateral to the strategy while it is enabled; if the
+    /// strategy refuses it, the collateral simply stays in the contract.
+    async fn deploy_to_strategy(&mut self, state: &mut LendingState, amount: u128, op: &str) {
+        let Some(strategy) = state.collateral_strategy.filter(|_| state.strategy_enabled) else { return };
+        let deploy = ActionIo::Transfer(strategy, to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let deployed = try_token_call(state.collateral_token, deploy, state.call_gas_limit).await;
+        self.profile_call(state.profiling, op, "strategy_deploy", gas_before);
+        if deployed {
+            state.collateral_in_strategy = state.collateral_in_strategy.saturating_add(amount);
+        }
+    }
+
+    /// Sends `amount` of collateral to `to`. Runs once the state change it pays for is
+    /// committed, so a refused transfer does not panic: the amount is credited to `to` as
+    /// claimable collateral (`claim_liquidation_surplus`) instead.
+    async fn pay_collateral(&mut self, state: &mut LendingState, to: ActorId, amount: u128, op: &str) {
+        if amount == 0 {
+            return;
+        }
+        let transfer = ActionIo::Transfer(to, to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let paid = try_token_call(state.collateral_token, transfer, state.call_gas_limit).await;
+        self.profile(state.profiling, op, gas_before);
+        if !paid {
+            credit_claimable(state, to, amount);
+            self.emit_event(LendingEvent::CollateralPayoutDeferred { to, amount }).expect("Event error");
+        }
+    }
+
+    /// Resolves the acting account like `get_actor`, and emits `SessionUsed` when it was
+    /// resolved through a session so indexers can follow delegated activity.
+    fn resolve_actor(
+        &mut self,
+        session_map: &SailsHashMap<ActorId, SessionData>,
+        msg_source: &ActorId,
+        session_for_account: &Option<ActorId>,
+        action: ActionsForSession,
+    ) -> ActorId {
+        let actor = get_actor(session_map, msg_source, session_for_account, action.clone());
+        if session_for_account.is_some() {
+            self.emit_event(LendingEvent::SessionUsed {
+                account: actor,
+                key: *msg_source,
+                action,
+            }).expect("Event error");
+        }
+        actor
+    }
+
+    /// Checks that the caller, directly or through a session, is the owner and consumes the
+    /// admin nonce. Returns the state for the owner-only update.
+    fn ensure_owner(&mut self, nonce: u64, session_for_account: Option<ActorId>) -> &'static mut LendingState {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let who = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::UpdateParams);
+
+        let state = LendingState::state_mut();
+        if who != state.owner {
+            panic!("Not owner");
+        }
+        use_admin_nonce(state, nonce);
+        state
+    }
+
+    /// Open a new loan. The caller must be the borrower authorized by session (or self if not signless).
+    /// The origination fee is withheld from the disbursed principal; if a `referrer` is given,
+    /// a `referral_fee` share of that fee is paid to them in debt tokens.
+    pub async fn open_loan(
+        &mut self,
+        collateral: u128,
+        principal: u128,
+        rate_mode: RateMode,
+        referrer: Option<ActorId>,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+
+        // Session-aware: If session_for_account is set, will use session verification
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::OpenLoan);
+
+        let state = LendingState::state_mut();
+        if state.paused {
+            panic!("{:?}", LendingError::Paused);
+        }
+        // After `set_tokens` nothing is lent until `verify_tokens` checked the new pair
+        if !state.tokens_verified {
+            panic!("Tokens not verified");
+        }
+
+        // Validate input
+        if principal < state.min_loan || principal > state.max_loan {
+            panic!("Loan principal out of bounds");
+        }
+        if collateral == 0 {
+            panic!("Must provide collateral");
+        }
+        // Check collateralization ratio
+        let collateral_token = state.collateral_token;
+        let gas_before = exec::gas_available();
+        let price = fetch_price(state, collateral_token).await;
+        self.profile(state.profiling, "open_loan.fetch_price", gas_before);
+        let price = price.unwrap_or_else(|e| panic!("{:?}", e));
+        let ratio = health_factor(collateral, principal, price, state.collateral_factor);
+        if ratio < state.min_collateral_ratio {
+            panic!("Insufficient collateral ratio");
+        }
+        if state.max_collateral_ratio > 0 && ratio > state.max_collateral_ratio {
+            panic!("{:?}", LendingError::OverCollateralized);
+        }
+
+        // Opens still awaiting their transfers hold a slot too
+        let pending_opens = state.pending_opens.values().map(|&n| n as usize).sum::<usize>();
+        if state.loans.len() + pending_opens >= state.max_loans as usize {
+            panic!("Loan limit reached"); 
+        }
+        // Pool mode lends LP deposits; otherwise loans are paid from the pre-funded contract balance
+        if state.liquidity_pool_enabled {
+            if principal > available_liquidity(state) {
+                panic!("{:?}", LendingError::InsufficientLiquidity);
+            }
+            // Keep a withdrawal buffer for LPs once the pool is heavily lent out
+            if state.max_utilization_for_borrow > 0 && utilization(state) > state.max_utilization_for_borrow {
+                panic!("{:?}", LendingError::UtilizationTooHigh);
+            }
+        }
+
+        // Anti-spam: enforce the minimum interval between a borrower's opens
+        let now = exec::block_timestamp();
+        if state.min_open_interval_ms > 0 {
+            if let Some(last) = state.last_open_ts.get(&borrower) {
+                if now < last.saturating_add(state.min_open_interval_ms) {
+                    panic!("{:?}", LendingError::TooFrequent);
+                }
+            }
+        }
+
+        let referrer = referrer.filter(|r| *r != ActorId::zero());
+        if referrer == Some(borrower) {
+            panic!("{:?}", LendingError::SelfReferral);
+        }
+        let fee = mul_div(principal, state.origination_fee, DECIMALS_FACTOR);
+        if state.max_fee_ratio > 0 && fee > mul_div(principal, state.max_fee_ratio, DECIMALS_FACTOR) {
+            panic!("{:?}", LendingError::FeeTooHigh);
+        }
+        let mut referral = match referrer {
+            Some(_) => mul_div(fee, state.referral_fee, DECIMALS_FACTOR),
+            None => 0,
+        };
+
+        // All invariants are checked before any token moves, so nothing can fail
+        // once collateral has been taken except the disbursal itself
+        let user_loans = state.user_loans.get(&borrower).map_or(0, |ids| ids.len());
+        let user_pending = state.pending_opens.get(&borrower).copied().unwrap_or(0) as usize;
+        if user_loans + user_pending >= MAX_USER_LOANS {
+            panic!("User loan limit reached"); 
+        }
+        state.next_loan_id.checked_add(1).expect("Loan id overflow");
+        state.total_collateral.checked_add(collateral).expect("Collateral overflow");
+        state.total_principal.checked_add(principal).expect("Principal overflow");
+
+        // Hold the loan slot and the anti-spam timestamp before the first token call, so
+        // concurrent opens are checked against them; both are undone if the open rolls back
+        *state.pending_opens.entry(borrower).or_default() += 1;
+        let previous_open_ts = state.last_open_ts.insert(borrower, now);
+
+        // Transfer collateral from user to contract
+        let transfer_from = ActionIo::TransferFrom(borrower, exec::program_id(), to_token_amount(collateral)).encode();
+        let gas_before = exec::gas_available();
+        let received = try_token_call(state.collateral_token, transfer_from, state.call_gas_limit).await;
+        self.profile(state.profiling, "open_loan.transfer_from", gas_before);
+        if !received {
+            cancel_open(state, borrower, now, previous_open_ts);
+            self.emit_event(LendingEvent::OpenRolledBack { borrower, collateral }).expect("Event error");
+            return LendingEvent::OpenRolledBack { borrower, collateral };
+        }
+
+        // Mint debt tokens to user (simulate FT transfer). A panic here would not undo the
+        // collateral transfer above, so on failure the collateral is returned (or credited as
+        // claimable if that is refused too) and the call ends.
+        let mint_debt = ActionIo::TransferFrom(exec::program_id(), borrower, to_token_amount(principal - fee)).encode();
+        let gas_before = exec::gas_available();
+        let disbursed = match msg::send_bytes_with_gas_for_reply(state.debt_token, mint_debt, state.call_gas_limit, 0, 0) {
+            Ok(future) => future.await.is_ok_and(|reply| expect_token_ok(reply).is_ok()),
+            Err(_) => false,
+        };
+        self.profile(state.profiling, "open_loan.mint_debt", gas_before);
+        if !disbursed {
+            cancel_open(state, borrower, now, previous_open_ts);
+            self.pay_collateral(state, borrower, collateral, "open_loan.return_collateral").await;
+            self.emit_event(LendingEvent::OpenRolledBack { borrower, collateral }).expect("Event error");
+            return LendingEvent::OpenRolledBack { borrower, collateral };
+        }
+        state.total_debt_issued = state.total_debt_issued.saturating_add(principal - fee);
+
+        // Pay the referrer's share of the origination fee; if that fails the share stays in reserve
+        if let Some(referrer) = referrer.filter(|_| referral > 0) {
+            let pay_referral = ActionIo::Transfer(referrer, to_token_amount(referral)).encode();
+            let gas_before = exec::gas_available();
+            let paid = match msg::send_bytes_with_gas_for_reply(state.debt_token, pay_referral, state.call_gas_limit, 0, 0) {
+                Ok(future) => future.await.is_ok_and(|reply| expect_token_ok(reply).is_ok()),
+                Err(_) => false,
+            };
+            self.profile(state.profiling, "open_loan.pay_referral", gas_before);
+            if paid {
+                self.emit_event(LendingEvent::ReferralPaid { referrer, amount: referral }).expect("Event error");
+            } else {
+                referral = 0;
+            }
+        }
+        state.protocol_reserve = state.protocol_reserve.saturating_add(fee - referral);
+        state.cumulative_revenue = state.cumulative_revenue.saturating_add(fee - referral);
+
+        let loan_id = state.next_loan_id;
+        let block = exec::block_timestamp() as u64; 
+
+        let loan = Loan {
+            borrower,
+            collateral,
+            principal,
+            interest_rate: current_borrow_rate(state),
+            start_timestamp: block,
+            closed_at: None,
+            accrued_so_far: 0,
+            capitalized_interest: 0,
+            // Accrual starts after the delay in force at open; later changes don't affect this loan
+            last_accrual_ts: block.saturating_add(state.accrual_start_delay_ms),
+            maturity_ts: (state.loan_term_ms > 0).then(|| block.saturating_add(state.loan_term_ms)),
+            protection_deposit: 0,
+            rate_mode,
+            last_withdraw_ts: 0,
+            liquidations: Vec::new(),
+            status: LoanStatus::Active,
+        };
+        state.loans.insert(loan_id, loan);
+        state.user_loans.entry(borrower).or_default().push(loan_id);
+        release_open_slot(state, borrower);
+        state.next_loan_id = state.next_loan_id.saturating_add(1);
+        state.total_collateral = state.total_collateral.saturating_add(collateral);
+        state.total_principal = state.total_principal.saturating_add(principal);
+
+        // Put the new collateral to work
+        self.deploy_to_strategy(state, collateral, "open_loan").await;
+
+        let via_key = session_for_account.map(|_| msg_src);
         self.emit_event(LendingEvent::LoanOpened {
             loan_id,
-            borrower,
-            collateral,
-            principal,
-        }).expect("Event error"); 
+            borrower,
+            collateral,
+            principal,
+            via_key,
+        }).expect("Event error"); 
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+
+        LendingEvent::LoanOpened {
+            loan_id,
+            borrower,
+            collateral,
+            principal,
+            via_key,
+        }
+    }
+
+    /// Repay a loan (with interest). Only authorized borrower via session or self.
+    /// The collateral goes to `collateral_to` if given (e.g. a vault), else to the borrower;
+    /// session calls cannot redirect it. If the debt tokens cannot be taken the loan stays open
+    /// (`RepayRolledBack`).
+    pub async fn repay(
+        &mut self,
+        loan_id: u64,
+        collateral_to: Option<ActorId>,
+        session_for_account: Option<ActorId>,
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+
+        let state = LendingState::state_mut();
+        let loan = active_loan(&mut state.loans, loan_id);
+        if loan.borrower != borrower {
+            panic!("Not loan owner");
+        }
+        let recipient = collateral_to.unwrap_or(borrower);
+        if recipient == ActorId::zero() {
+            panic!("{:?}", LendingError::InvalidActor);
+        }
+        // A session key may repay, but only the borrower may send the collateral elsewhere
+        if recipient != borrower && session_for_account.is_some() {
+            panic!("{:?}", LendingError::InvalidActor);
+        }
+        // Any unused protection deposit is returned with the collateral
+        let deposit = apply_protection(loan);
+        // Calculate interest
+        let current_block = exec::block_timestamp() as u64;
+        settle_accrual(loan, current_block, state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+
+        // The burn and the kept share add up to exactly `total_owed`: the reserve share is
+        // rounded down and the burn takes the remainder, so no dust is charged or stranded
+        let total_owed = loan.principal.saturating_add(loan.accrued_so_far);
+        let reserve_share = mul_div(settled_interest(loan), state.reserve_factor, DECIMALS_FACTOR);
+        let (collateral, principal, lent) = (loan.collateral, loan.principal, lent_principal(loan));
+
+        // Close the loan before the token calls so it cannot be repaid or liquidated again
+        // meanwhile
+        loan.status = LoanStatus::Closed;
+        loan.closed_at = Some(current_block);
+        unindex_loan(&mut state.user_loans, borrower, loan_id);
+        state.total_collateral = state.total_collateral.saturating_sub(collateral);
+        state.total_principal = state.total_principal.saturating_sub(principal);
+
+        // Burn user debt tokens for repayment; the protocol's share of the interest is kept as
+        // reserve, and in pool mode the lent principal goes back to the pool
+        let restore = if state.liquidity_pool_enabled { lent } else { 0 };
+        let keep = reserve_share + restore;
+        let taken = self.take_repayment(state, borrower, total_owed - keep, keep, "repay").await;
+        let Ok(burned) = taken else {
+            // Nothing was paid: reopen the loan without panicking, which would keep it closed
+            if let Some(loan) = state.loans.get_mut(&loan_id) {
+                loan.status = LoanStatus::Active;
+                loan.closed_at = None;
+                loan.collateral -= deposit;
+                loan.protection_deposit = deposit;
+            }
+            reindex_loan(&mut state.user_loans, borrower, loan_id);
+            state.total_collateral = state.total_collateral.saturating_add(collateral);
+            state.total_principal = state.total_principal.saturating_add(principal);
+            self.emit_event(LendingEvent::RepayRolledBack { loan_id, borrower }).expect("Event error");
+            return LendingEvent::RepayRolledBack { loan_id, borrower };
+        };
+        state.total_debt_burned = state.total_debt_burned.saturating_add(burned);
+        book_reserve_share(state, reserve_share);
+
+        // Return collateral to user (or the destination they chose)
+        self.recall_from_strategy(state, collateral, "repay").await;
+        self.pay_collateral(state, recipient, collateral, "repay.return_collateral").await;
+
+        let via_key = session_for_account.map(|_| msg_src);
+        self.emit_event(LendingEvent::Repaid {
+            loan_id,
+            borrower,
+            via_key,
+        }).expect("Event error"); 
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+
+        LendingEvent::Repaid {
+            loan_id,
+            borrower,
+            via_key,
+        }
+    }
+
+    /// Repay part of a loan: `amount` (capped at what is owed) covers accrued interest first,
+    /// then principal. Collateral stays locked until the principal reaches zero, at which point
+    /// the loan is closed and all collateral goes to `collateral_to` (or the borrower; session
+    /// calls cannot redirect it). If the debt tokens cannot be taken nothing changes
+    /// (`RepayRolledBack`). Only authorized borrower via session or self.
+    pub async fn repay_partial(
+        &mut self,
+        loan_id: u64,
+        amount: u128,
+        collateral_to: Option<ActorId>,
+        session_for_account: Option<ActorId>,
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+
+        let state = LendingState::state_mut();
+        let loan = active_loan(&mut state.loans, loan_id);
+        if loan.borrower != borrower {
+            panic!("Not loan owner");
+        }
+        let recipient = collateral_to.unwrap_or(borrower);
+        if recipient == ActorId::zero() {
+            panic!("{:?}", LendingError::InvalidActor);
+        }
+        // A session key may repay, but only the borrower may send the collateral elsewhere
+        if recipient != borrower && session_for_account.is_some() {
+            panic!("{:?}", LendingError::InvalidActor);
+        }
+        if amount == 0 {
+            panic!("Amount must be positive");
+        }
+        let now = exec::block_timestamp();
+        settle_accrual(loan, now, state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+
+        let paid = amount.min(loan.principal.saturating_add(loan.accrued_so_far));
+        let from_interest = paid.min(loan.accrued_so_far);
+        let from_principal = paid - from_interest;
+        // Capitalized interest is repaid before the lent principal and shares like interest
+        let from_capitalized = from_principal.min(loan.capitalized_interest);
+        let reserve_share = mul_div(from_interest + from_capitalized, state.reserve_factor, DECIMALS_FACTOR);
+        let closes = from_principal == loan.principal;
+
+        // Book the repayment before the token calls so a concurrent repay, consolidation or
+        // liquidation sees the reduced debt, and a closed loan cannot release its collateral twice
+        loan.accrued_so_far -= from_interest;
+        loan.principal -= from_principal;
+        loan.capitalized_interest -= from_capitalized;
+        state.total_principal = state.total_principal.saturating_sub(from_principal);
+        let remaining_principal = loan.principal;
+        let (mut deposit, mut collateral) = (0, 0);
+        if closes {
+            // Fully repaid: close the loan and release all of its collateral
+            deposit = apply_protection(loan);
+            collateral = loan.collateral;
+            loan.status = LoanStatus::Closed;
+            loan.closed_at = Some(now);
+            unindex_loan(&mut state.user_loans, borrower, loan_id);
+            state.total_collateral = state.total_collateral.saturating_sub(collateral);
+        }
+
+        // In pool mode the lent principal share goes back to the pool instead of being burned
+        let restore = if state.liquidity_pool_enabled { from_principal - from_capitalized } else { 0 };
+        let keep = reserve_share + restore;
+        let taken = self.take_repayment(state, borrower, paid - keep, keep, "repay_partial").await;
+        let Ok(burned) = taken else {
+            // Nothing was paid: restore the debt without panicking, which would keep the booking
+            if let Some(loan) = state.loans.get_mut(&loan_id) {
+                loan.accrued_so_far = loan.accrued_so_far.saturating_add(from_interest);
+                loan.principal = loan.principal.saturating_add(from_principal);
+                loan.capitalized_interest = loan.capitalized_interest.saturating_add(from_capitalized);
+                if closes {
+                    loan.status = LoanStatus::Active;
+                    loan.closed_at = None;
+                    loan.collateral -= deposit;
+                    loan.protection_deposit = deposit;
+                }
+            }
+            if closes {
+                reindex_loan(&mut state.user_loans, borrower, loan_id);
+                state.total_collateral = state.total_collateral.saturating_add(collateral);
+            }
+            state.total_principal = state.total_principal.saturating_add(from_principal);
+            self.emit_event(LendingEvent::RepayRolledBack { loan_id, borrower }).expect("Event error");
+            return LendingEvent::RepayRolledBack { loan_id, borrower };
+        };
+        state.total_debt_burned = state.total_debt_burned.saturating_add(burned);
+        book_reserve_share(state, reserve_share);
+
+        self.emit_event(LendingEvent::PartialRepaid {
+            loan_id,
+            amount: paid,
+            remaining_principal,
+        }).expect("Event error");
+
+        if !closes {
+            debug_assert_eq!(
+                recomputed_totals(state),
+                (state.total_collateral, state.total_principal),
+                "Totals out of sync with loans"
+            );
+            self.emit_event(LendingEvent::TotalsChanged {
+                total_collateral: state.total_collateral,
+                total_principal: state.total_principal,
+            }).expect("Event error");
+            return LendingEvent::PartialRepaid {
+                loan_id,
+                amount: paid,
+                remaining_principal,
+            };
+        }
+
+        self.recall_from_strategy(state, collateral, "repay_partial").await;
+        self.pay_collateral(state, recipient, collateral, "repay_partial.return_collateral").await;
+        debug_assert_eq!(
+            recomputed_totals(state),
+            (state.total_collateral, state.total_principal),
+            "Totals out of sync with loans"
+        );
+
+        let via_key = session_for_account.map(|_| msg_src);
+        self.emit_event(LendingEvent::Repaid {
+            loan_id,
+            borrower,
+            via_key,
+        }).expect("Event error");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+
+        LendingEvent::Repaid {
+            loan_id,
+            borrower,
+            via_key,
+        }
+    }
+
+    /// Roll the interest accrued on an active loan into its principal when interest
+    /// capitalization is enabled, so it accrues interest itself from now on. Principal never
+    /// grows past `max_loan`; interest beyond that stays accrued. Capitalized interest still
+    /// counts toward `max_interest_multiple` and pays the reserve share when repaid. Only the
+    /// borrower or the owner, via session or self.
+    pub fn capitalize_interest(&mut self, loan_id: u64, session_for_account: Option<ActorId>) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let who = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+
+        let state = LendingState::state_mut();
+        if !state.interest_capitalization {
+            panic!("Interest capitalization disabled");
+        }
+        let loan = active_loan(&mut state.loans, loan_id);
+        // Compounding raises the borrower's debt, so third parties may not trigger it
+        if who != loan.borrower && who != state.owner {
+            panic!("Not loan owner");
+        }
+        settle_accrual(loan, exec::block_timestamp(), state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+        let amount = loan.accrued_so_far.min(state.max_loan.saturating_sub(loan.principal));
+        if amount == 0 {
+            panic!("Nothing to capitalize");
+        }
+        loan.accrued_so_far -= amount;
+        loan.principal += amount;
+        loan.capitalized_interest += amount;
+        let principal = loan.principal;
+        state.total_principal = state.total_principal.saturating_add(amount);
+
+        self.emit_event(LendingEvent::InterestCapitalized { loan_id, amount, principal }).expect("Event error");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+        LendingEvent::InterestCapitalized { loan_id, amount, principal }
+    }
+
+    /// Add `amount` of collateral to an active loan. Dust top-ups are rejected: the amount must be
+    /// at least `min_collateral_topup`, or raise the health factor (at the last known price) by
+    /// more than zero and at least `min_health_improvement`; the result may not exceed
+    /// `max_collateral_ratio`. Only authorized borrower via session or self.
+    pub async fn add_collateral(
+        &mut self,
+        loan_id: u64,
+        amount: u128,
+        session_for_account: Option<ActorId>,
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::OpenLoan);
+
+        let state = LendingState::state_mut();
+        let price = last_known_price(state, state.collateral_token);
+        let loan = active_loan(&mut state.loans, loan_id);
+        if loan.borrower != borrower {
+            panic!("Not loan owner");
+        }
+        if amount == 0 {
+            panic!("Amount must be positive");
+        }
+        let new_collateral = loan.collateral.checked_add(amount).expect("Collateral overflow");
+        state.total_collateral.checked_add(amount).expect("Collateral overflow");
+        let after = health_factor(new_collateral, loan.principal, price, state.collateral_factor);
+        // Same ceiling as at open, so a top-up can't park idle collateral in the contract
+        if state.max_collateral_ratio > 0 && after > state.max_collateral_ratio {
+            panic!("{:?}", LendingError::OverCollateralized);
+        }
+        let big_enough = state.min_collateral_topup > 0 && amount >= state.min_collateral_topup;
+        if !big_enough {
+            let before = health_factor(loan.collateral, loan.principal, price, state.collateral_factor);
+            let improvement = after.saturating_sub(before);
+            if improvement == 0 || improvement < state.min_health_improvement {
+                panic!("{:?}", LendingError::AmountTooSmall);
+            }
+        }
+
+        let transfer_from = ActionIo::TransferFrom(borrower, exec::program_id(), to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let reply = msg::send_bytes_with_gas_for_reply(state.collateral_token, transfer_from, state.call_gas_limit, 0, 0)
+            .expect("Collateral transfer failed")
+            .await
+            .expect("No reply for collateral transfer");
+        expect_token_ok(reply).unwrap_or_else(|e| panic!("{:?}", e));
+        self.profile(state.profiling, "add_collateral.transfer_from", gas_before);
+
+        // The loan may have been closed while the transfer was in flight: give the funds back
+        let loan = match state.loans.get_mut(&loan_id) {
+            Some(loan) if loan.status == LoanStatus::Active => loan,
+            _ => {
+                return_collateral(state.collateral_token, borrower, amount, state.call_gas_limit).await;
+                panic!("{:?}", LendingError::AlreadyClosed);
+            }
+        };
+        loan.collateral = loan.collateral.saturating_add(amount);
+        state.total_collateral = state.total_collateral.saturating_add(amount);
+
+        // Put the new collateral to work like at open
+        self.deploy_to_strategy(state, amount, "add_collateral").await;
+
+        self.emit_event(LendingEvent::CollateralAdded { loan_id, amount }).expect("Event error");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+        LendingEvent::CollateralAdded { loan_id, amount }
+    }
+
+    /// Take `amount` of collateral out of an active loan, as long as it stays above the minimum
+    /// collateral ratio. After a withdrawal the loan can't be withdrawn from again until
+    /// `collateral_withdraw_cooldown_ms` has passed. The withdrawal is booked before the transfer
+    /// and undone if the transfer is refused (`WithdrawalRolledBack`).
+    /// Only authorized borrower via session or self.
+    pub async fn withdraw_collateral(
+        &mut self,
+        loan_id: u64,
+        amount: u128,
+        session_for_account: Option<ActorId>,
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+
+        let state = LendingState::state_mut();
+        let loan = active_loan(&mut state.loans, loan_id);
+        if loan.borrower != borrower {
+            panic!("Not loan owner");
+        }
+        if amount == 0 || amount > loan.collateral {
+            panic!("Invalid withdraw amount");
+        }
+        let now = exec::block_timestamp();
+        if loan.last_withdraw_ts > 0
+            && now < loan.last_withdraw_ts.saturating_add(state.collateral_withdraw_cooldown_ms)
+        {
+            panic!("{:?}", LendingError::WithdrawCooldown);
+        }
+
+        let collateral_token = state.collateral_token;
+        let gas_before = exec::gas_available();
+        let price = fetch_price(state, collateral_token).await;
+        self.profile(state.profiling, "withdraw_collateral.fetch_price", gas_before);
+        let price = price.unwrap_or_else(|e| panic!("{:?}", e));
+        // Re-check after the oracle call: the loan may have changed, or been withdrawn from,
+        // meanwhile
+        let loan = active_loan(&mut state.loans, loan_id);
+        let now = exec::block_timestamp();
+        if loan.last_withdraw_ts > 0
+            && now < loan.last_withdraw_ts.saturating_add(state.collateral_withdraw_cooldown_ms)
+        {
+            panic!("{:?}", LendingError::WithdrawCooldown);
+        }
+        let remaining = loan.collateral.checked_sub(amount).expect("Invalid withdraw amount");
+        if health_factor(remaining, loan.principal, price, state.collateral_factor) < state.min_collateral_ratio {
+            panic!("Insufficient collateral ratio");
+        }
+
+        // Book the withdrawal before the token calls so a concurrent withdrawal is checked
+        // against the reduced collateral and the new cooldown
+        let previous_withdraw_ts = loan.last_withdraw_ts;
+        loan.collateral = remaining;
+        loan.last_withdraw_ts = now;
+        state.total_collateral = state.total_collateral.saturating_sub(amount);
+
+        self.recall_from_strategy(state, amount, "withdraw_collateral").await;
+        let transfer = ActionIo::Transfer(borrower, to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let paid = try_token_call(collateral_token, transfer, state.call_gas_limit).await;
+        self.profile(state.profiling, "withdraw_collateral.return_collateral", gas_before);
+        if !paid {
+            match state.loans.get_mut(&loan_id).filter(|loan| loan.status == LoanStatus::Active) {
+                // Undo the booking without panicking, which would keep it
+                Some(loan) => {
+                    loan.collateral = loan.collateral.saturating_add(amount);
+                    if loan.last_withdraw_ts == now {
+                        loan.last_withdraw_ts = previous_withdraw_ts;
+                    }
+                    state.total_collateral = state.total_collateral.saturating_add(amount);
+                    self.emit_event(LendingEvent::WithdrawalRolledBack { loan_id, amount }).expect("Event error");
+                    return LendingEvent::WithdrawalRolledBack { loan_id, amount };
+                }
+                // The loan was closed meanwhile without this collateral: it stays claimable
+                None => {
+                    credit_claimable(state, borrower, amount);
+                    self.emit_event(LendingEvent::CollateralPayoutDeferred { to: borrower, amount }).expect("Event error");
+                }
+            }
+        }
+
+        self.emit_event(LendingEvent::CollateralWithdrawn { loan_id, amount }).expect("Event error");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+        LendingEvent::CollateralWithdrawn { loan_id, amount }
+    }
+
+    /// Add collateral to a loan's protection deposit, which tops the loan up when it would
+    /// otherwise be liquidated. Only authorized borrower via session or self.
+    pub async fn fund_protection(
+        &mut self,
+        loan_id: u64,
+        amount: u128,
+        session_for_account: Option<ActorId>,
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::OpenLoan);
+
+        let state = LendingState::state_mut();
+        if active_loan(&mut state.loans, loan_id).borrower != borrower {
+            panic!("Not loan owner");
+        }
+        if amount == 0 {
+            panic!("Amount must be positive");
+        }
+        state.total_collateral.checked_add(amount).expect("Collateral overflow");
+
+        let transfer_from = ActionIo::TransferFrom(borrower, exec::program_id(), to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let reply = msg::send_bytes_with_gas_for_reply(state.collateral_token, transfer_from, state.call_gas_limit, 0, 0)
+            .expect("Collateral transfer failed")
+            .await
+            .expect("No reply for collateral transfer");
+        expect_token_ok(reply).unwrap_or_else(|e| panic!("{:?}", e));
+        self.profile(state.profiling, "fund_protection.transfer_from", gas_before);
+
+        // The loan may have been closed while the transfer was in flight: give the funds back
+        let loan = match state.loans.get_mut(&loan_id) {
+            Some(loan) if loan.status == LoanStatus::Active => loan,
+            _ => {
+                return_collateral(state.collateral_token, borrower, amount, state.call_gas_limit).await;
+                panic!("{:?}", LendingError::AlreadyClosed);
+            }
+        };
+        loan.protection_deposit = loan.protection_deposit.saturating_add(amount);
+        state.total_collateral = state.total_collateral.saturating_add(amount);
+
+        self.emit_event(LendingEvent::ProtectionFunded { loan_id, amount }).expect("Event error");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+        LendingEvent::ProtectionFunded { loan_id, amount }
+    }
+
+    /// Repay several of the borrower's active loans at once: the combined principal+interest is
+    /// burned in one token call and the combined collateral returned in one transfer (credited
+    /// as claimable if refused). If the debt tokens cannot be taken the loans stay open
+    /// (`ConsolidationRolledBack`).
+    /// Only authorized borrower via session or self.
+    pub async fn consolidate_and_repay(
+        &mut self,
+        loan_ids: Vec<u64>,
+        session_for_account: Option<ActorId>,
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+
+        if loan_ids.is_empty() || loan_ids.len() > MAX_CONSOLIDATE_LOANS {
+            panic!("Invalid number of loans");
+        }
+        let mut unique = loan_ids.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        if unique.len() != loan_ids.len() {
+            panic!("Duplicate loan id");
+        }
+
+        let state = LendingState::state_mut();
+        let now = exec::block_timestamp();
+        let (mut total_owed, mut reserve_share, mut collateral, mut principal) = (0u128, 0u128, 0u128, 0u128);
+        let mut lent = 0u128;
+        let mut deposits = Vec::with_capacity(loan_ids.len());
+        for &loan_id in &loan_ids {
+            let loan = active_loan(&mut state.loans, loan_id);
+            if loan.borrower != borrower {
+                panic!("Not loan owner");
+            }
+            deposits.push(apply_protection(loan));
+            settle_accrual(loan, now, state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+            total_owed = total_owed
+                .saturating_add(loan.principal)
+                .saturating_add(loan.accrued_so_far);
+            // Rounded per loan, exactly as separate repays would
+            reserve_share = reserve_share
+                .saturating_add(mul_div(settled_interest(loan), state.reserve_factor, DECIMALS_FACTOR));
+            collateral = collateral.saturating_add(loan.collateral);
+            principal = principal.saturating_add(loan.principal);
+            lent = lent.saturating_add(lent_principal(loan));
+        }
+        // Close the loans before the token calls so none of them can be repaid or liquidated
+        // again meanwhile, as in `repay_with_collateral`
+        for &loan_id in &loan_ids {
+            if let Some(loan) = state.loans.get_mut(&loan_id) {
+                loan.status = LoanStatus::Closed;
+                loan.closed_at = Some(now);
+            }
+            unindex_loan(&mut state.user_loans, borrower, loan_id);
+        }
+        state.total_collateral = state.total_collateral.saturating_sub(collateral);
+        state.total_principal = state.total_principal.saturating_sub(principal);
+
+        // In pool mode the lent principal goes back to the pool instead of being burned
+        let restore = if state.liquidity_pool_enabled { lent } else { 0 };
+        let keep = reserve_share + restore;
+        let taken = self.take_repayment(state, borrower, total_owed - keep, keep, "consolidate_and_repay").await;
+        let Ok(burned) = taken else {
+            // Nothing was paid: reopen the loans without panicking, which would keep them closed
+            for (&loan_id, &deposit) in loan_ids.iter().zip(&deposits) {
+                if let Some(loan) = state.loans.get_mut(&loan_id) {
+                    loan.status = LoanStatus::Active;
+                    loan.closed_at = None;
+                    loan.collateral -= deposit;
+                    loan.protection_deposit = deposit;
+                }
+                reindex_loan(&mut state.user_loans, borrower, loan_id);
+            }
+            state.total_collateral = state.total_collateral.saturating_add(collateral);
+            state.total_principal = state.total_principal.saturating_add(principal);
+            self.emit_event(LendingEvent::ConsolidationRolledBack {
+                borrower,
+                loan_ids: loan_ids.clone(),
+            }).expect("Event error");
+            return LendingEvent::ConsolidationRolledBack { borrower, loan_ids };
+        };
+        state.total_debt_burned = state.total_debt_burned.saturating_add(burned);
+        book_reserve_share(state, reserve_share);
+
+        self.recall_from_strategy(state, collateral, "consolidate_and_repay").await;
+        self.pay_collateral(state, borrower, collateral, "consolidate_and_repay.return_collateral").await;
+
+        let via_key = session_for_account.map(|_| msg_src);
+        for &loan_id in &loan_ids {
+            self.emit_event(LendingEvent::Repaid {
+                loan_id,
+                borrower,
+                via_key,
+            }).expect("Event error");
+        }
+        self.emit_event(LendingEvent::LoansConsolidated {
+            borrower,
+            loan_ids: loan_ids.clone(),
+            repaid: total_owed,
+        }).expect("Event error");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+
+        LendingEvent::LoansConsolidated {
+            borrower,
+            loan_ids,
+            repaid: total_owed,
+        }
+    }
+
+    /// Close a loan using its own collateral instead of debt tokens. At the oracle price, enough
+    /// collateral to cover principal+interest goes to the owner (as with liquidation) and the
+    /// rest is returned to the borrower. Only authorized borrower via session or self.
+    pub async fn repay_with_collateral(
+        &mut self,
+        loan_id: u64,
+        session_for_account: Option<ActorId>,
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+
+        let state = LendingState::state_mut();
+        if active_loan(&mut state.loans, loan_id).borrower != borrower {
+            panic!("Not loan owner");
+        }
+        let collateral_token = state.collateral_token;
+        let gas_before = exec::gas_available();
+        let price = fetch_price(state, collateral_token).await;
+        self.profile(state.profiling, "repay_with_collateral.fetch_price", gas_before);
+        let price = price.unwrap_or_else(|e| panic!("{:?}", e));
+
+        // Re-check after the oracle call: the loan may have been closed meanwhile
+        let loan = active_loan(&mut state.loans, loan_id);
+        let now = exec::block_timestamp();
+        apply_protection(loan);
+        settle_accrual(loan, now, state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+        let owed = loan.principal.saturating_add(loan.accrued_so_far);
+        // Round up so the protocol is never paid less than the debt
+        let used = mul_div_up(owed, DECIMALS_FACTOR, price);
+        if used > loan.collateral {
+            panic!("Collateral does not cover the debt");
+        }
+        let surplus = loan.collateral - used;
+
+        // Close the loan before the token calls so it cannot be repaid twice meanwhile
+        loan.status = LoanStatus::Closed;
+        loan.closed_at = Some(now);
+        let (collateral, principal, lent) = (loan.collateral, loan.principal, lent_principal(loan));
+        unindex_loan(&mut state.user_loans, borrower, loan_id);
+        state.total_collateral = state.total_collateral.saturating_sub(collateral);
+        state.total_principal = state.total_principal.saturating_sub(principal);
+        write_off_pool_principal(state, lent);
+
+        // The loan is closed either way: a refused payout is credited as claimable collateral
+        self.recall_from_strategy(state, collateral, "repay_with_collateral").await;
+        let owner = state.owner;
+        self.pay_collateral(state, owner, used, "repay_with_collateral.return_collateral").await;
+        self.pay_collateral(state, borrower, surplus, "repay_with_collateral.return_collateral").await;
+
+        let via_key = session_for_account.map(|_| msg_src);
+        self.emit_event(LendingEvent::Repaid {
+            loan_id,
+            borrower,
+            via_key,
+        }).expect("Event error");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+
+        LendingEvent::Repaid {
+            loan_id,
+            borrower,
+            via_key,
+        }
+    }
+
+    /// Liquidate undercollateralized loan, or a term loan still open `post_maturity_grace_ms`
+    /// after maturity. Anyone can call (only allowlisted liquidators when permissioned
+    /// liquidation is on); session not required. In `Restore` mode an undercollateralized loan
+    /// is only `liquidate_amount`-ed for the debt that brings it back to `min_collateral_ratio`.
+    pub async fn liquidate(
+        &mut self,
+        loan_id: u64,
+        _session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        // No session required on liquidation, but param included for interface consistency
+        let liquidator = msg::source();
+        let state = LendingState::state_mut();
+        // Overdue loans are closed in full: restoring the ratio does not end their term
+        if state.liquidation_mode == LiquidationMode::Restore
+            && !is_overdue(active_loan(&mut state.loans, loan_id), exec::block_timestamp(), state.post_maturity_grace_ms)
+        {
+            return self.liquidate_partial(loan_id, None).await;
+        }
+        if state.permissioned_liquidation && !state.liquidators.contains(&liquidator) {
+            panic!("{:?}", LendingError::NotLiquidator);
+        }
+        let loan = active_loan(&mut state.loans, loan_id);
+        // Dust loans are not worth the gas and events of a liquidation
+        if loan.principal < state.min_liquidation_principal {
+            panic!("{:?}", LendingError::LoanTooSmall);
+        }
+
+        // On-chain price check for liquidation
+        let collateral_token = state.collateral_token;
+        let gas_before = exec::gas_available();
+        let price = fetch_price(state, collateral_token).await;
+        self.profile(state.profiling, "liquidate.fetch_price", gas_before);
+        let price = price.unwrap_or_else(|e| panic!("{:?}", e));
+        // Re-check after the oracle call: the loan may have been closed meanwhile
+        let loan = active_loan(&mut state.loans, loan_id);
+        settle_accrual(loan, exec::block_timestamp(), state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+        let ratio = health_factor(loan.collateral, loan.principal, price, state.collateral_factor);
+        // Term loans left unpaid past maturity and the grace period are liquidated even when healthy
+        let overdue = is_overdue(loan, exec::block_timestamp(), state.post_maturity_grace_ms);
+        if ratio >= state.min_collateral_ratio && !overdue {
+            panic!("Loan safe; can't liquidate");
+        }
+        // The borrower's protection deposit is used first; liquidate only if still underwater
+        if loan.protection_deposit > 0 {
+            let amount = apply_protection(loan);
+            self.emit_event(LendingEvent::ProtectionApplied { loan_id, amount }).expect("Event error");
+            if !overdue
+                && health_factor(loan.collateral, loan.principal, price, state.collateral_factor) >= state.min_collateral_ratio
+            {
+                return LendingEvent::ProtectionApplied { loan_id, amount };
+            }
+        }
+        // Counted only once the loan is really liquidated, not when its deposit saved it
+        if let Err(e) = throttle_liquidation(state, exec::block_timestamp()) {
+            panic!("{:?}", e);
+        }
+        let loan = active_loan(&mut state.loans, loan_id);
+        let value = collateral_value(loan.collateral, price);
+
+        // Keeper reward out of the seized collateral, bounded by the daily budget
+        let today = exec::block_timestamp() / DAY_MS;
+        if today != state.reward_day {
+            state.reward_day = today;
+            state.rewards_paid_today = 0;
+        }
+        let reward = mul_div(loan.collateral, state.liquidator_reward, DECIMALS_FACTOR)
+            .min(state.daily_reward_cap.saturating_sub(state.rewards_paid_today));
+        state.rewards_paid_today = state.rewards_paid_today.saturating_add(reward);
+
+        // Collateral beyond the debt plus the liquidation bonus stays claimable by the borrower
+        let owed = loan.principal.saturating_add(loan.accrued_so_far);
+        let owed_collateral = mul_div_up(owed, DECIMALS_FACTOR, price.max(1));
+        let mut bonus = mul_div(owed_collateral, state.liquidation_bonus, DECIMALS_FACTOR);
+        if state.max_liquidation_bonus_abs > 0 {
+            bonus = bonus.min(state.max_liquidation_bonus_abs);
+        }
+        let surplus = (loan.collateral - reward).saturating_sub(owed_collateral.saturating_add(bonus));
+        let borrower = loan.borrower;
+        let collateral = loan.collateral;
+
+        // Book the liquidation before the transfers so nothing else can act on the loan meanwhile
+        let (principal, lent) = (loan.principal, lent_principal(loan));
+        state.total_collateral = state.total_collateral.saturating_sub(collateral);
+        state.total_principal = state.total_principal.saturating_sub(principal);
+        loan.status = LoanStatus::Liquidated;
+        loan.closed_at = Some(exec::block_timestamp());
+        unindex_loan(&mut state.user_loans, borrower, loan_id);
+        let count = state.liquidation_counts.entry(borrower).or_default();
+        *count = count.saturating_add(1);
+        write_off_pool_principal(state, lent);
+
+        if surplus > 0 {
+            let credited = state.liquidation_surplus.entry(borrower).or_default();
+            *credited = credited.saturating_add(surplus);
+            state.total_liquidation_surplus = state.total_liquidation_surplus.saturating_add(surplus);
+            self.emit_event(LendingEvent::LiquidationSurplusCredited {
+                loan_id,
+                borrower,
+                amount: surplus,
+            }).expect("Event error");
+        }
+
+        // Book whatever principal+interest the seized collateral does not cover as protocol bad debt
+        let shortfall = owed.saturating_sub(value);
+        if shortfall > 0 {
+            state.bad_debt = state.bad_debt.saturating_add(shortfall);
+            self.emit_event(LendingEvent::BadDebtRealized {
+                loan_id,
+                amount: shortfall,
+            }).expect("Event error");
+        }
+
+        // The reward to the keeper, the rest of the seized collateral to the owner; the loan is
+        // closed either way, so a refused transfer is credited as claimable collateral
+        self.recall_from_strategy(state, collateral, "liquidate").await;
+        if reward > 0 {
+            self.pay_collateral(state, liquidator, reward, "liquidate.return_collateral").await;
+            self.emit_event(LendingEvent::LiquidatorRewarded { liquidator, amount: reward }).expect("Event error");
+        }
+        let owner = state.owner;
+        self.pay_collateral(state, owner, collateral - reward - surplus, "liquidate.transfer").await;
+
+        self.emit_event(LendingEvent::Liquidated {
+            loan_id,
+            borrower,
+        }).expect("Event error"); 
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+
+        LendingEvent::Liquidated {
+            loan_id,
+            borrower,
+        }
+    }
+
+    /// Liquidate part of an undercollateralized loan: the liquidator burns `repay_amount` of its
+    /// debt (at most `close_factor` of what is owed) and receives the matching collateral plus
+    /// `liquidation_bonus`. The loan stays active unless its debt is fully covered.
+    pub async fn liquidate_amount(
+        &mut self,
+        loan_id: u64,
+        repay_amount: u128,
+        _session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        self.liquidate_partial(loan_id, Some(repay_amount)).await
+    }
+
+    /// Partial liquidation behind `liquidate_amount`, and behind `liquidate` in `Restore` mode
+    /// (`repay_amount` None: repay what brings the loan back to `min_collateral_ratio`, within
+    /// the close factor).
+    async fn liquidate_partial(&mut self, loan_id: u64, repay_amount: Option<u128>) -> LendingEvent {
+        let liquidator = msg::source();
+        let state = LendingState::state_mut();
+        if state.permissioned_liquidation && !state.liquidators.contains(&liquidator) {
+            panic!("{:?}", LendingError::NotLiquidator);
+        }
+        let loan = active_loan(&mut state.loans, loan_id);
+        // Dust loans are not worth the gas and events of a liquidation
+        if loan.principal < state.min_liquidation_principal {
+            panic!("{:?}", LendingError::LoanTooSmall);
+        }
+
+        let collateral_token = state.collateral_token;
+        let gas_before = exec::gas_available();
+        let price = fetch_price(state, collateral_token).await;
+        self.profile(state.profiling, "liquidate_amount.fetch_price", gas_before);
+        let price = price.unwrap_or_else(|e| panic!("{:?}", e));
+        // Re-check after the oracle call: the loan may have been closed meanwhile
+        let loan = active_loan(&mut state.loans, loan_id);
+        let now = exec::block_timestamp();
+        settle_accrual(loan, now, state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+        let ratio = health_factor(loan.collateral, loan.principal, price, state.collateral_factor);
+        if ratio >= state.min_collateral_ratio {
+            panic!("Loan safe; can't liquidate");
+        }
+        if loan.protection_deposit > 0 {
+            let amount = apply_protection(loan);
+            self.emit_event(LendingEvent::ProtectionApplied { loan_id, amount }).expect("Event error");
+            if health_factor(loan.collateral, loan.principal, price, state.collateral_factor) >= state.min_collateral_ratio {
+                return LendingEvent::ProtectionApplied { loan_id, amount };
+            }
+        }
+        let owed = loan.principal.saturating_add(loan.accrued_so_far);
+        let max_repay = mul_div(owed, state.close_factor, DECIMALS_FACTOR).min(owed);
+        let repay_amount = match repay_amount {
+            Some(amount) => amount,
+            None => restore_repay_amount(loan, price, state.collateral_factor, state.liquidation_bonus, state.min_collateral_ratio)
+                .unwrap_or(owed)
+                .min(max_repay),
+        };
+        if repay_amount == 0 || repay_amount > max_repay {
+            panic!("{:?}", LendingError::InvalidRepayAmount);
+        }
+        let base = mul_div(repay_amount, DECIMALS_FACTOR, price);
+        let mut bonus = mul_div(base, state.liquidation_bonus, DECIMALS_FACTOR);
+        if state.max_liquidation_bonus_abs > 0 {
+            bonus = bonus.min(state.max_liquidation_bonus_abs);
+        }
+        let seized = base.saturating_add(bonus).min(loan.collateral);
+        // Cover accrued interest first, then principal
+        let from_interest = repay_amount.min(loan.accrued_so_far);
+        let from_principal = (repay_amount - from_interest).min(loan.principal);
+        let from_capitalized = from_principal.min(loan.capitalized_interest);
+        if let Err(e) = throttle_liquidation(state, now) {
+            panic!("{:?}", e);
+        }
+        let window_start = state.liquidation_window_start;
+
+        // Book the liquidation before the burn so nothing else can act on the old debt meanwhile
+        let loan = active_loan(&mut state.loans, loan_id);
+        loan.accrued_so_far -= from_interest;
+        loan.principal -= from_principal;
+        loan.capitalized_interest -= from_capitalized;
+        loan.collateral -= seized;
+        let borrower = loan.borrower;
+        let closed = loan.principal == 0 && loan.accrued_so_far == 0;
+        let leftover = if closed { loan.collateral } else { 0 };
+        if closed {
+            loan.status = LoanStatus::Liquidated;
+            loan.closed_at = Some(now);
+        }
+        state.total_principal = state.total_principal.saturating_sub(from_principal);
+        state.total_collateral = state.total_collateral.saturating_sub(seized.saturating_add(leftover));
+
+        // In pool mode the lent principal share goes back to the pool instead of being burned
+        let restore = if state.liquidity_pool_enabled { from_principal - from_capitalized } else { 0 };
+        let taken = self.take_repayment(state, liquidator, repay_amount - restore, restore, "liquidate_amount").await;
+        let burned = match taken {
+            Ok(burned) => burned,
+            Err(_) => {
+                // The liquidator paid nothing: undo the booking without panicking, which would
+                // keep it since it was committed before the burn
+                if let Some(loan) = state.loans.get_mut(&loan_id) {
+                    loan.accrued_so_far = loan.accrued_so_far.saturating_add(from_interest);
+                    loan.principal = loan.principal.saturating_add(from_principal);
+                    loan.capitalized_interest = loan.capitalized_interest.saturating_add(from_capitalized);
+                    loan.collateral = loan.collateral.saturating_add(seized);
+                    if closed {
+                        loan.status = LoanStatus::Active;
+                        loan.closed_at = None;
+                    }
+                }
+                state.total_principal = state.total_principal.saturating_add(from_principal);
+                state.total_collateral = state.total_collateral.saturating_add(seized.saturating_add(leftover));
+                refund_liquidation_slot(state, window_start);
+                self.emit_event(LendingEvent::LiquidationRolledBack { loan_id, liquidator }).expect("Event error");
+                return LendingEvent::LiquidationRolledBack { loan_id, liquidator };
+            }
+        };
+        state.total_debt_burned = state.total_debt_burned.saturating_add(burned);
+
+        if let Some(loan) = state.loans.get_mut(&loan_id) {
+            if loan.liquidations.len() >= MAX_LIQUIDATION_RECORDS {
+                loan.liquidations.remove(0);
+            }
+            loan.liquidations.push(LiquidationRecord {
+                timestamp: now,
+                debt_repaid: repay_amount,
+                collateral_seized: seized,
+                liquidator,
+            });
+        }
+        if closed {
+            unindex_loan(&mut state.user_loans, borrower, loan_id);
+            let count = state.liquidation_counts.entry(borrower).or_default();
+            *count = count.saturating_add(1);
+        }
+
+        // Only the collateral paid out is recalled; a refused payout is credited as claimable
+        self.recall_from_strategy(state, seized.saturating_add(leftover), "liquidate_amount").await;
+        self.pay_collateral(state, liquidator, seized, "liquidate_amount.return_collateral").await;
+        self.pay_collateral(state, borrower, leftover, "liquidate_amount.return_collateral").await;
+
+        self.emit_event(LendingEvent::PartiallyLiquidated {
+            loan_id,
+            repaid: repay_amount,
+            seized,
+        }).expect("Event error");
+        if closed {
+            self.emit_event(LendingEvent::Liquidated { loan_id, borrower }).expect("Event error");
+        }
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+
+        LendingEvent::PartiallyLiquidated {
+            loan_id,
+            repaid: repay_amount,
+            seized,
+        }
+    }
+
+    /// Withdraw the collateral left over from the caller's liquidated loans once their debt and
+    /// the liquidation bonus were covered, plus any collateral payout to the caller that was
+    /// refused and credited instead. If the transfer is refused again the amount stays
+    /// claimable and `TransferRejected` is returned. Borrower authorized by session or self.
+    pub async fn claim_liquidation_surplus(
+        &mut self,
+        session_for_account: Option<ActorId>
+    ) -> Result<LendingEvent, LendingError> {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let borrower = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+
+        let state = LendingState::state_mut();
+        let amount = state.liquidation_surplus.remove(&borrower).unwrap_or(0);
+        if amount == 0 {
+            panic!("No surplus to claim");
+        }
+        // Cleared before the transfer so a concurrent claim cannot pay out twice
+        state.total_liquidation_surplus = state.total_liquidation_surplus.saturating_sub(amount);
+
+        let transfer = ActionIo::Transfer(borrower, to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let paid = try_token_call(state.collateral_token, transfer, state.call_gas_limit).await;
+        self.profile(state.profiling, "claim_liquidation_surplus.return_collateral", gas_before);
+        if !paid {
+            // Re-credit without panicking, which would keep the balance cleared
+            credit_claimable(state, borrower, amount);
+            return Err(LendingError::TransferRejected);
+        }
+
+        self.emit_event(LendingEvent::LiquidationSurplusClaimed { borrower, amount }).expect("Event error");
+        Ok(LendingEvent::LiquidationSurplusClaimed { borrower, amount })
+    }
+
+    /// Deposit debt tokens as lendable liquidity. Provider authorized by session or self.
+    pub async fn deposit_liquidity(
+        &mut self,
+        amount: u128,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let provider = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::ManageLiquidity);
+
+        let state = LendingState::state_mut();
+        if !state.liquidity_pool_enabled {
+            panic!("{:?}", LendingError::PoolDisabled);
+        }
+        if amount == 0 {
+            panic!("Amount must be positive");
+        }
+
+        let transfer_from = ActionIo::TransferFrom(provider, exec::program_id(), to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let reply = msg::send_bytes_with_gas_for_reply(state.debt_token, transfer_from, state.call_gas_limit, 0, 0)
+            .expect("Liquidity transfer failed")
+            .await
+            .expect("No reply for liquidity transfer");
+        expect_token_ok(reply).unwrap_or_else(|e| panic!("{:?}", e));
+        self.profile(state.profiling, "deposit_liquidity.transfer_from", gas_before);
+
+        let balance = state.lp_balances.entry(provider).or_default();
+        *balance = balance.checked_add(amount).expect("Liquidity overflow");
+        state.total_liquidity = state.total_liquidity.checked_add(amount).expect("Liquidity overflow");
+
+        self.emit_event(LendingEvent::LiquidityDeposited { provider, amount }).expect("Event error");
+        LendingEvent::LiquidityDeposited { provider, amount }
+    }
+
+    /// Withdraw previously deposited liquidity, limited to what is not lent out. If the transfer
+    /// is refused the balance is re-credited and `TransferRejected` returned.
+    pub async fn withdraw_liquidity(
+        &mut self,
+        amount: u128,
+        session_for_account: Option<ActorId>
+    ) -> Result<LendingEvent, LendingError> {
+        let msg_src = msg::source();
+        let sessions = Storage::get_session_map();
+        let provider = self.resolve_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::ManageLiquidity);
+
+        let state = LendingState::state_mut();
+        let balance = state.lp_balances.get(&provider).copied().unwrap_or(0);
+        if amount == 0 || amount > balance {
+            panic!("Invalid withdraw amount");
+        }
+        if amount > available_liquidity(state) {
+            panic!("{:?}", LendingError::InsufficientLiquidity);
+        }
+
+        // Update balances before the transfer so a concurrent withdraw cannot double-spend
+        if balance == amount {
+            state.lp_balances.remove(&provider);
+        } else {
+            state.lp_balances.insert(provider, balance - amount);
+        }
+        state.total_liquidity -= amount;
+
+        let transfer = ActionIo::Transfer(provider, to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let paid = try_token_call(state.debt_token, transfer, state.call_gas_limit).await;
+        self.profile(state.profiling, "withdraw_liquidity.transfer", gas_before);
+        if !paid {
+            // Re-credit without panicking, which would keep the balance debited
+            let balance = state.lp_balances.entry(provider).or_default();
+            *balance = balance.saturating_add(amount);
+            state.total_liquidity = state.total_liquidity.saturating_add(amount);
+            return Err(LendingError::TransferRejected);
+        }
+
+        self.emit_event(LendingEvent::LiquidityWithdrawn { provider, amount }).expect("Event error");
+        Ok(LendingEvent::LiquidityWithdrawn { provider, amount })
+    }
+
+    /// Set new owner/admin (must be authorized owner, by session or self).
+    pub fn set_owner(
+        &mut self,
+        new_owner: ActorId,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.owner = new_owner;
+        self.emit_event(LendingEvent::OwnerSet(new_owner)).expect("Event err"); 
+        LendingEvent::OwnerSet(new_owner)
+    }
+
+    /// Update lending params (base rate, min, max) - owner only (session or self).
+    /// New bounds only apply to new opens: existing loans are grandfathered, see `query_over_max_loans`.
+    pub fn update_params(
+        &mut self,
+        new_rate: u128,
+        min_loan: u128,
+        max_loan: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if let Err(e) = validate_params(state, new_rate, min_loan, max_loan) {
+            panic!("{:?}", e);
+        }
+        let previous_rate = current_borrow_rate(state);
+        state.base_interest_rate = new_rate;
+        if state.rate_history.len() >= MAX_RATE_HISTORY {
+            state.rate_history.remove(0);
+        }
+        state.rate_history.push((exec::block_timestamp(), new_rate));
+        state.min_loan = min_loan;
+        state.max_loan = max_loan;
+        checkpoint_floating_rate(state, previous_rate, exec::block_timestamp());
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set `min_loan`/`max_loan` in whole debt tokens, converted with the verified `debt_decimals`
+    /// (see `verify_tokens`); the limits are stored in smallest units - owner only (session or self).
+    pub fn set_loan_limits_in_tokens(
+        &mut self,
+        min_tokens: u128,
+        max_tokens: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if !state.tokens_verified {
+            panic!("Tokens not verified");
+        }
+        let min_loan = whole_to_raw(min_tokens, state.debt_decimals).expect("Loan limit overflow");
+        let max_loan = whole_to_raw(max_tokens, state.debt_decimals).expect("Loan limit overflow");
+        if min_loan == 0 {
+            panic!("Loan thresholds invalid");
+        }
+        if let Err(e) = validate_params(state, state.base_interest_rate, min_loan, max_loan) {
+            panic!("{:?}", e);
+        }
+        state.min_loan = min_loan;
+        state.max_loan = max_loan;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the interest rate floor and ceiling (`max_rate` 0 = no ceiling) - owner only (session or self).
+    pub fn set_rate_bounds(
+        &mut self,
+        min_rate: u128,
+        max_rate: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if max_rate != 0 && min_rate > max_rate {
+            panic!("{:?}", LendingError::RateOutOfBounds);
+        }
+        let previous_rate = current_borrow_rate(state);
+        state.min_interest_rate = min_rate;
+        state.max_interest_rate = max_rate;
+        checkpoint_floating_rate(state, previous_rate, exec::block_timestamp());
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the delay (ms) before interest starts accruing on newly opened loans. Unlike the
+    /// interest-free period it is fixed per loan at open - owner only (session or self).
+    pub fn set_accrual_start_delay(
+        &mut self,
+        delay_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.accrual_start_delay_ms = delay_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Turn `CallProfile` events around every external call of a command (token, strategy and
+    /// oracle calls, one event per call) on or off - owner only (session or self).
+    pub fn set_profiling(
+        &mut self,
+        enabled: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.profiling = enabled;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the term (ms) of newly opened loans; 0 opens perpetual loans - owner only (session or self).
+    pub fn set_loan_term(
+        &mut self,
+        term_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.loan_term_ms = term_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the interest-free period (ms) counted from each loan's start - owner only (session or self).
+    pub fn set_interest_free_period(
+        &mut self,
+        period_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.interest_free_period_ms = period_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the maximum number of loans the contract stores - owner only (session or self).
+    pub fn set_max_loans(
+        &mut self,
+        max_loans: u32,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.max_loans = max_loans;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Transfer tokens accidentally sent to the contract - owner only (session or self).
+    /// For the collateral and debt tokens only the surplus beyond what backs loans,
+    /// LP liquidity and the protocol reserve can be rescued.
+    pub async fn rescue_tokens(
+        &mut self,
+        token: ActorId,
+        to: ActorId,
+        amount: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if to == ActorId::zero() || amount == 0 {
+            panic!("Invalid rescue");
+        }
+
+        let required = if token == state.collateral_token {
+            // Collateral deployed to the strategy is not in the contract's balance
+            Some(
+                state.total_collateral
+                    .saturating_sub(state.collateral_in_strategy)
+                    .saturating_add(state.total_liquidation_surplus),
+            )
+        } else if token == state.debt_token {
+            Some(
+                available_liquidity(state)
+                    .saturating_add(state.protocol_reserve)
+                    .saturating_add(state.rounding_reserve),
+            )
+        } else {
+            None
+        };
+        if let Some(required) = required {
+            let gas_before = exec::gas_available();
+            let balance = token_balance(token, exec::program_id(), state.call_gas_limit).await;
+            self.profile(state.profiling, "rescue_tokens.token_balance", gas_before);
+            if amount > balance.saturating_sub(required) {
+                panic!("Cannot rescue backing funds");
+            }
+        }
+
+        let transfer = ActionIo::Transfer(to, to_token_amount(amount)).encode();
+        let gas_before = exec::gas_available();
+        let reply = msg::send_bytes_with_gas_for_reply(token, transfer, state.call_gas_limit, 0, 0)
+            .expect("Rescue transfer failed")
+            .await
+            .expect("No reply for rescue transfer");
+        expect_token_ok(reply).unwrap_or_else(|e| panic!("{:?}", e));
+        self.profile(state.profiling, "rescue_tokens.transfer", gas_before);
+
+        self.emit_event(LendingEvent::TokensRescued { token, to, amount }).expect("Event err");
+        LendingEvent::TokensRescued { token, to, amount }
+    }
+
+    /// Check that both tokens respond like VFT contracts with the same decimals and store them;
+    /// new loans are only opened once this passed - owner only (session or self). Needed again
+    /// after `set_tokens`.
+    pub async fn verify_tokens(
+        &mut self,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        let gas_before = exec::gas_available();
+        let collateral_decimals = token_decimals(state.collateral_token, state.call_gas_limit).await;
+        self.profile(state.profiling, "verify_tokens.token_decimals", gas_before);
+        let gas_before = exec::gas_available();
+        let debt_decimals = token_decimals(state.debt_token, state.call_gas_limit).await;
+        self.profile(state.profiling, "verify_tokens.token_decimals", gas_before);
+        // Amounts of the two tokens are compared without normalization, so decimals must match
+        if collateral_decimals != debt_decimals {
+            panic!(
+                "Token decimals differ: collateral {}, debt {}",
+                collateral_decimals, debt_decimals
+            );
+        }
+
+        state.collateral_decimals = collateral_decimals;
+        state.debt_decimals = debt_decimals;
+        state.tokens_verified = true;
+
+        self.emit_event(LendingEvent::TokensVerified { collateral_decimals, debt_decimals }).expect("Event err");
+        LendingEvent::TokensVerified { collateral_decimals, debt_decimals }
+    }
+
+    /// Point the contract at migrated token contracts. Only allowed while no loan is active (or
+    /// being opened) and nothing else is owed in the token being replaced - claimable collateral
+    /// for the collateral token, LP liquidity and reserves for the debt token - so nothing is left
+    /// behind in the old token; decimals must be re-checked with `verify_tokens` afterwards -
+    /// owner only (session or self).
+    pub fn set_tokens(
+        &mut self,
+        collateral: Option<ActorId>,
+        debt: Option<ActorId>,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if state.loans.values().any(|loan| loan.status == LoanStatus::Active) || !state.pending_opens.is_empty() {
+            panic!("Active loans exist");
+        }
+        let collateral_token = collateral.unwrap_or(state.collateral_token);
+        let debt_token = debt.unwrap_or(state.debt_token);
+        if collateral_token == ActorId::zero() || debt_token == ActorId::zero() {
+            panic!("Token addresses cannot be zero");
+        }
+        if collateral_token == debt_token {
+            panic!("Collateral and debt token must differ");
+        }
+        if collateral_token != state.collateral_token && state.collateral_in_strategy > 0 {
+            panic!("Collateral still deployed to strategy");
+        }
+        if collateral_token != state.collateral_token && state.total_liquidation_surplus > 0 {
+            panic!("Collateral still claimable");
+        }
+        if debt_token != state.debt_token
+            && (state.total_liquidity > 0
+                || !state.lp_balances.is_empty()
+                || state.protocol_reserve > 0
+                || state.rounding_reserve > 0)
+        {
+            panic!("Debt tokens still held for LPs or reserve");
+        }
+
+        state.collateral_token = collateral_token;
+        state.debt_token = debt_token;
+        state.tokens_verified = false;
+
+        self.emit_event(LendingEvent::TokensUpdated { collateral_token, debt_token }).expect("Event err");
+        LendingEvent::TokensUpdated { collateral_token, debt_token }
+    }
+
+    /// Set the yield strategy idle collateral is deployed to and whether new collateral is sent
+    /// there. The strategy can only be replaced once everything deployed has been recalled
+    /// - owner only (session or self).
+    pub fn set_collateral_strategy(
+        &mut self,
+        strategy: Option<ActorId>,
+        enabled: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if strategy != state.collateral_strategy && state.collateral_in_strategy > 0 {
+            panic!("Collateral still deployed to the strategy");
+        }
+        state.collateral_strategy = strategy;
+        state.strategy_enabled = enabled && strategy.is_some();
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Withdraw all deployed collateral back from the strategy - owner only (session or self).
+    pub async fn recall_strategy_collateral(
+        &mut self,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        let deployed = state.collateral_in_strategy;
+        // Nothing is booked before the recall, so a refused one can simply fail the call
+        if !self.recall_from_strategy(state, deployed, "recall_strategy_collateral").await {
+            panic!("{:?}", LendingError::TransferRejected);
+        }
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set (or clear) the price oracle used to value collateral - owner only (session or self).
+    pub fn set_price_oracle(
+        &mut self,
+        oracle: Option<ActorId>,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.price_oracle = oracle;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set how old (ms) a cached price may be for `fetch_price` to fall back to it when the
+    /// oracle fails; 0 never falls back - owner only (session or self).
+    pub fn set_max_price_age(
+        &mut self,
+        max_age_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.max_price_age_ms = max_age_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the origination fee (fraction of principal) and the referrer's share of it,
+    /// both in DECIMALS_FACTOR - owner only (session or self).
+    pub fn set_fees(
+        &mut self,
+        origination_fee: u128,
+        referral_fee: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if origination_fee > DECIMALS_FACTOR || referral_fee > DECIMALS_FACTOR {
+            panic!("Fee above 100%");
+        }
+        state.origination_fee = origination_fee;
+        state.referral_fee = referral_fee;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the maximum upfront fees a loan may carry as a share of its principal, in
+    /// DECIMALS_FACTOR (0 = unlimited) - owner only (session or self).
+    pub fn set_max_fee_ratio(
+        &mut self,
+        max_fee_ratio: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.max_fee_ratio = max_fee_ratio;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Recycle the reserve share of repaid interest into lendable liquidity, or hold it as
+    /// reserve - owner only (session or self).
+    pub fn set_auto_compound_reserve(
+        &mut self,
+        enabled: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.auto_compound_reserve = enabled;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Fund loans from LP deposits: new loans are capped by available liquidity and repaid
+    /// principal is returned to the pool instead of burned. Off by default, so deployments
+    /// that pre-fund the contract keep lending without LP deposits - owner only (session or self).
+    pub fn set_liquidity_pool_enabled(
+        &mut self,
+        enabled: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.liquidity_pool_enabled = enabled;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Move fixed-rate loans to the current borrow rate after a base-rate change: interest
+    /// accrued so far is settled at the old rate, then each loan keeps a fixed rate at the new
+    /// value (`to_base`) or becomes floating. Ids that are not active fixed loans are skipped and
+    /// counted in the returned `LoansRebased`; at most MAX_REBASE_BATCH ids per call - owner only
+    /// (session or self).
+    pub fn rebase_loans(
+        &mut self,
+        loan_ids: Vec<u64>,
+        to_base: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if loan_ids.len() > MAX_REBASE_BATCH {
+            panic!("Batch too large");
+        }
+        let now = exec::block_timestamp();
+        let rate = current_borrow_rate(state);
+        let rate_mode = if to_base { RateMode::Fixed } else { RateMode::Floating };
+        let (mut rebased, mut skipped) = (0u32, 0u32);
+        for loan_id in loan_ids {
+            let loan = match state.loans.get_mut(&loan_id) {
+                Some(loan) if loan.status == LoanStatus::Active && loan.rate_mode == RateMode::Fixed => loan,
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            rebased += 1;
+            settle_accrual(loan, now, state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+            loan.interest_rate = rate;
+            loan.rate_mode = rate_mode;
+            self.emit_event(LendingEvent::LoanRebased {
+                loan_id,
+                interest_rate: rate,
+                rate_mode,
+            }).expect("Event err");
+        }
+        self.emit_event(LendingEvent::LoansRebased { rebased, skipped }).expect("Event err");
+        LendingEvent::LoansRebased { rebased, skipped }
+    }
+
+    /// Reconcile the books with the debt token: the difference between the contract's balance
+    /// and the debt tokens it accounts for (available liquidity plus protocol reserve) becomes
+    /// the rounding reserve, up to MAX_ROUNDING_RESERVE. A larger surplus, or any deficit, is
+    /// reported as `unabsorbed` - owner only (session or self).
+    pub async fn sync_rounding_reserve(
+        &mut self,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        let gas_before = exec::gas_available();
+        let balance = token_balance(state.debt_token, exec::program_id(), state.call_gas_limit).await;
+        self.profile(state.profiling, "sync_rounding_reserve.token_balance", gas_before);
+
+        let booked = available_liquidity(state).saturating_add(state.protocol_reserve);
+        let (reserve, unabsorbed) = if balance >= booked {
+            let surplus = balance - booked;
+            (surplus.min(MAX_ROUNDING_RESERVE), surplus.saturating_sub(MAX_ROUNDING_RESERVE))
+        } else {
+            (0, booked - balance)
+        };
+        state.rounding_reserve = reserve;
+
+        self.emit_event(LendingEvent::RoundingReserveSynced { reserve, unabsorbed }).expect("Event err");
+        LendingEvent::RoundingReserveSynced { reserve, unabsorbed }
+    }
+
+    /// Restrict the actions that may be performed through a session key to `allowlist` (e.g.
+    /// drop `OpenLoan` during a wind-down); `None` allows every action. Direct calls are not
+    /// affected - owner only (session or self).
+    pub fn set_action_allowlist(
+        &mut self,
+        allowlist: Option<Vec<ActionsForSession>>,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.action_allowlist = allowlist;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
 
-        LendingEvent::LoanOpened {
-            loan_id,
-            borrower,
-            collateral,
-            principal,
+    /// Enable or disable `capitalize_interest` - owner only (session or self).
+    pub fn set_interest_capitalization(
+        &mut self,
+        enabled: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.interest_capitalization = enabled;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the minimum collateral ratio (health factor, in DECIMALS_FACTOR, above 100%) loans
+    /// are opened at and liquidated below; `max_ltv` follows as its reciprocal - owner only
+    /// (session or self).
+    pub fn set_min_collateral_ratio(
+        &mut self,
+        min_ratio: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        set_collateral_limit(state, min_ratio);
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the same limit as a maximum loan-to-value (in DECIMALS_FACTOR, below 100%);
+    /// `min_collateral_ratio` becomes its reciprocal - owner only (session or self).
+    pub fn set_max_ltv(
+        &mut self,
+        max_ltv: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if max_ltv == 0 || max_ltv >= DECIMALS_FACTOR {
+            panic!("LTV must be between 0 and 100%");
+        }
+        set_collateral_limit(state, min_ratio_for_ltv(max_ltv));
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set how long (ms) after maturity a healthy term loan can still be repaid before it
+    /// becomes liquidatable - owner only (session or self).
+    pub fn set_post_maturity_grace(
+        &mut self,
+        grace_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.post_maturity_grace_ms = grace_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Choose whether `liquidate` seizes the whole loan or only restores it to
+    /// `min_collateral_ratio` - owner only (session or self).
+    pub fn set_liquidation_mode(
+        &mut self,
+        mode: LiquidationMode,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.liquidation_mode = mode;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Cap the interest a loan can accrue at `principal * max_interest_multiple / DECIMALS_FACTOR`;
+    /// 0 removes the cap - owner only (session or self).
+    pub fn set_max_interest_multiple(
+        &mut self,
+        max_interest_multiple: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.max_interest_multiple = max_interest_multiple;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the utilization (in DECIMALS_FACTOR) above which `open_loan` is rejected; 0 disables
+    /// the limit - owner only (session or self).
+    pub fn set_max_utilization_for_borrow(
+        &mut self,
+        max_utilization: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if max_utilization > DECIMALS_FACTOR {
+            panic!("Utilization limit above 100%");
+        }
+        state.max_utilization_for_borrow = max_utilization;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the share of repaid interest kept as protocol reserve, in DECIMALS_FACTOR - owner only (session or self).
+    pub fn set_reserve_factor(
+        &mut self,
+        reserve_factor: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if reserve_factor > DECIMALS_FACTOR {
+            panic!("Reserve factor above 100%");
+        }
+        state.reserve_factor = reserve_factor;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the collateral factor (share of collateral value usable as borrowing power),
+    /// in DECIMALS_FACTOR - owner only (session or self).
+    pub fn set_collateral_factor(
+        &mut self,
+        collateral_factor: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if collateral_factor == 0 || collateral_factor > DECIMALS_FACTOR {
+            panic!("Collateral factor out of range");
+        }
+        state.collateral_factor = collateral_factor;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the liquidator reward (share of seized collateral, in DECIMALS_FACTOR) and the
+    /// total reward paid per day - owner only (session or self).
+    pub fn set_liquidator_reward(
+        &mut self,
+        liquidator_reward: u128,
+        daily_reward_cap: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if liquidator_reward > DECIMALS_FACTOR {
+            panic!("Reward above 100%");
+        }
+        state.liquidator_reward = liquidator_reward;
+        state.daily_reward_cap = daily_reward_cap;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the close factor and liquidation bonus used by `liquidate_amount` (both in
+    /// DECIMALS_FACTOR) - owner only (session or self).
+    pub fn set_liquidation_params(
+        &mut self,
+        close_factor: u128,
+        liquidation_bonus: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if close_factor == 0 || close_factor > DECIMALS_FACTOR {
+            panic!("Close factor must be in (0, 100%]");
+        }
+        state.close_factor = close_factor;
+        state.liquidation_bonus = liquidation_bonus;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the gas forwarded with each token call - owner only (session or self).
+    pub fn set_call_gas_limit(
+        &mut self,
+        gas_limit: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if gas_limit == 0 {
+            panic!("Gas limit must be positive");
+        }
+        state.call_gas_limit = gas_limit;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Cap the liquidation bonus of a single `liquidate_amount` at `max_bonus` collateral units
+    /// (0 = uncapped) - owner only (session or self).
+    pub fn set_max_liquidation_bonus_abs(
+        &mut self,
+        max_bonus: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.max_liquidation_bonus_abs = max_bonus;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Cap the number of liquidations per `window_ms` window (`max_per_window` 0 = unlimited)
+    /// - owner only (session or self).
+    pub fn set_liquidation_throttle(
+        &mut self,
+        max_per_window: u32,
+        window_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.max_liquidations_per_window = max_per_window;
+        state.liquidation_window_ms = window_ms;
+        state.liquidation_window_start = exec::block_timestamp();
+        state.liquidations_in_window = 0;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Restrict liquidation to allowlisted liquidators, or reopen it to anyone - owner only (session or self).
+    pub fn set_permissioned_liquidation(
+        &mut self,
+        enabled: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.permissioned_liquidation = enabled;
+        self.emit_event(LendingEvent::PermissionedLiquidationSet(enabled)).expect("Event err");
+        LendingEvent::PermissionedLiquidationSet(enabled)
+    }
+
+    /// Add a liquidator to the allowlist - owner only (session or self).
+    pub fn add_liquidator(
+        &mut self,
+        liquidator: ActorId,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if !state.liquidators.contains(&liquidator) {
+            state.liquidators.push(liquidator);
+        }
+        self.emit_event(LendingEvent::LiquidatorAdded(liquidator)).expect("Event err");
+        LendingEvent::LiquidatorAdded(liquidator)
+    }
+
+    /// Remove a liquidator from the allowlist - owner only (session or self).
+    pub fn remove_liquidator(
+        &mut self,
+        liquidator: ActorId,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.liquidators.retain(|l| *l != liquidator);
+        self.emit_event(LendingEvent::LiquidatorRemoved(liquidator)).expect("Event err");
+        LendingEvent::LiquidatorRemoved(liquidator)
+    }
+
+    /// Set the maximum collateral ratio accepted for new loans (0 = unlimited) - owner only (session or self).
+    pub fn set_max_collateral_ratio(
+        &mut self,
+        max_ratio: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if max_ratio != 0 && max_ratio <= state.min_collateral_ratio {
+            panic!("Max ratio must exceed min collateral ratio");
+        }
+        state.max_collateral_ratio = max_ratio;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Recompute `total_collateral`/`total_principal` from the active loans and correct any
+    /// drift; `TotalsReconciled` is emitted only if a correction was needed - owner only
+    /// (session or self).
+    pub fn reconcile_totals(
+        &mut self,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        let (total_collateral, total_principal) = recomputed_totals(state);
+        let corrected = total_collateral != state.total_collateral || total_principal != state.total_principal;
+        let event = LendingEvent::TotalsReconciled {
+            total_collateral,
+            total_principal,
+            corrected,
+        };
+        if corrected {
+            state.total_collateral = total_collateral;
+            state.total_principal = total_principal;
+            self.emit_event(event.clone()).expect("Event err");
+        }
+        event
+    }
+
+    /// Recognize `amount` of bad debt as a loss covered by the protocol reserve, reducing both
+    /// - owner only (session or self).
+    pub fn write_off_bad_debt(
+        &mut self,
+        amount: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if amount == 0 || amount > state.bad_debt || amount > state.protocol_reserve {
+            panic!("Invalid write-off amount");
+        }
+        state.bad_debt -= amount;
+        state.protocol_reserve -= amount;
+        let remaining = state.bad_debt;
+        self.emit_event(LendingEvent::BadDebtWrittenOff { amount, remaining }).expect("Event err");
+        LendingEvent::BadDebtWrittenOff { amount, remaining }
+    }
+
+    /// Set the minimum principal a loan needs to be liquidatable; 0 disables it.
+    /// Owner only (session or self).
+    pub fn set_min_liquidation_principal(
+        &mut self,
+        min_principal: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.min_liquidation_principal = min_principal;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Emergency exit while paused: returns the collateral (including protection deposits) of
+    /// up to `max_loans` active loans, lowest id first, to their borrowers without repayment and
+    /// closes them; their unpaid debt is booked as bad debt. Call again until `remaining` is 0.
+    /// A loan whose transfer fails stays active for the next call - owner only (session or self).
+    pub async fn emergency_release_all(
+        &mut self,
+        max_loans: u32,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if !state.paused {
+            panic!("Not paused");
+        }
+        let mut ids: Vec<u64> = state
+            .loans
+            .iter()
+            .filter(|(_, loan)| loan.status == LoanStatus::Active)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids.truncate(max_loans.min(MAX_EMERGENCY_BATCH) as usize);
+        let batch_collateral = ids
+            .iter()
+            .filter_map(|id| state.loans.get(id))
+            .fold(0u128, |acc, loan| acc.saturating_add(loan.collateral).saturating_add(loan.protection_deposit));
+        self.recall_from_strategy(state, batch_collateral, "emergency_release_all").await;
+
+        let now = exec::block_timestamp();
+        let mut released = 0u32;
+        for loan_id in ids {
+            let loan = match state.loans.get_mut(&loan_id) {
+                Some(loan) if loan.status == LoanStatus::Active => loan,
+                _ => continue,
+            };
+            settle_accrual(loan, now, state.interest_free_period_ms, state.max_interest_multiple, &state.floating_rate_checkpoints);
+            let borrower = loan.borrower;
+            let amount = loan.collateral.saturating_add(loan.protection_deposit);
+            // Closed before the transfer so a concurrent call cannot release it twice
+            loan.status = LoanStatus::Closed;
+            loan.closed_at = Some(now);
+
+            let transfer = ActionIo::Transfer(borrower, to_token_amount(amount)).encode();
+            let gas_before = exec::gas_available();
+            let sent = try_token_call(state.collateral_token, transfer, state.call_gas_limit).await;
+            self.profile(state.profiling, "emergency_release_all.transfer", gas_before);
+            let loan = state.loans.get_mut(&loan_id).expect("Loan not found");
+            if !sent {
+                loan.status = LoanStatus::Active;
+                loan.closed_at = None;
+                continue;
+            }
+            let (principal, lent) = (loan.principal, lent_principal(loan));
+            state.total_collateral = state.total_collateral.saturating_sub(amount);
+            state.total_principal = state.total_principal.saturating_sub(principal);
+            state.bad_debt = state.bad_debt.saturating_add(principal.saturating_add(loan.accrued_so_far));
+            unindex_loan(&mut state.user_loans, borrower, loan_id);
+            write_off_pool_principal(state, lent);
+            released += 1;
+            self.emit_event(LendingEvent::EmergencyCollateralReleased { loan_id, borrower, amount }).expect("Event err");
+        }
+
+        let remaining = state.loans.values().filter(|loan| loan.status == LoanStatus::Active).count() as u32;
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event err");
+        self.emit_event(LendingEvent::EmergencyReleaseProgress { released, remaining }).expect("Event err");
+        LendingEvent::EmergencyReleaseProgress { released, remaining }
+    }
+
+    /// Pause or unpause new borrowing, with an optional reason of at most MAX_PAUSE_REASON_LEN
+    /// bytes shown to users; unpausing clears the reason - owner only (session or self).
+    pub fn set_paused(
+        &mut self,
+        paused: bool,
+        reason: Option<Vec<u8>>,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        if reason.as_ref().is_some_and(|reason| reason.len() > MAX_PAUSE_REASON_LEN) {
+            panic!("{:?}", LendingError::PauseReasonTooLong);
+        }
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.paused = paused;
+        state.pause_reason = if paused { reason } else { None };
+
+        let event = LendingEvent::PausedSet {
+            paused,
+            reason: state.pause_reason.clone(),
+        };
+        self.emit_event(event.clone()).expect("Event err");
+        event
+    }
+
+    /// Set the dust limits for `add_collateral`: top-ups of at least `min_amount` (0 = none) are
+    /// always accepted, smaller ones must raise the health factor by `min_health_improvement`.
+    /// Owner only (session or self).
+    pub fn set_collateral_topup_limits(
+        &mut self,
+        min_amount: u128,
+        min_health_improvement: u128,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.min_collateral_topup = min_amount;
+        state.min_health_improvement = min_health_improvement;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the cooldown (ms) between two collateral withdrawals from the same loan; 0 disables it.
+    /// Owner only (session or self).
+    pub fn set_collateral_withdraw_cooldown(
+        &mut self,
+        cooldown_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.collateral_withdraw_cooldown_ms = cooldown_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the maximum remaining lifetime (ms) a session may have to act on lending; 0 disables it.
+    /// Owner only (session or self).
+    pub fn set_max_session_lifetime(
+        &mut self,
+        lifetime_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.max_session_lifetime_ms = lifetime_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Set the minimum interval (ms) between two loan opens by the same borrower; 0 disables it.
+    /// Owner only (session or self).
+    pub fn set_min_open_interval(
+        &mut self,
+        interval_ms: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.min_open_interval_ms = interval_ms;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Enable/disable migration mode, which unlocks `seed_loans` - owner only (session or self).
+    pub fn set_migration_mode(
+        &mut self,
+        enabled: bool,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        state.migration_mode = enabled;
+        self.emit_event(LendingEvent::MigrationModeSet(enabled)).expect("Event err");
+        LendingEvent::MigrationModeSet(enabled)
+    }
+
+    /// Move the loan id counter forward (never back, so ids are not reused) - owner only,
+    /// migration mode only.
+    pub fn set_next_loan_id(
+        &mut self,
+        id: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if !state.migration_mode {
+            panic!("Migration mode disabled");
+        }
+        if id < state.next_loan_id {
+            panic!("Loan id can only increase");
+        }
+        state.next_loan_id = id;
+        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
+        LendingEvent::ParamsUpdated
+    }
+
+    /// Import pre-formed loans without token transfers - owner only, migration mode only.
+    pub fn seed_loans(
+        &mut self,
+        loans: Vec<SeedLoan>,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if !state.migration_mode {
+            panic!("Migration mode disabled");
+        }
+        if loans.len() > MAX_SEED_BATCH {
+            panic!("Seed batch too large");
+        }
+        if state.loans.len() + loans.len() > state.max_loans as usize {
+            panic!("Loan limit reached");
+        }
+
+        let count = loans.len() as u32;
+        for seed in loans {
+            if seed.borrower == ActorId::zero() || seed.principal == 0 {
+                panic!("Invalid seed loan");
+            }
+            let loan_id = state.next_loan_id;
+            let user_loans = state.user_loans.entry(seed.borrower).or_default();
+            if user_loans.len() >= MAX_USER_LOANS {
+                panic!("User loan limit reached");
+            }
+            user_loans.push(loan_id);
+            state.loans.insert(loan_id, Loan {
+                borrower: seed.borrower,
+                collateral: seed.collateral,
+                principal: seed.principal,
+                interest_rate: seed.interest_rate,
+                start_timestamp: seed.start_ts,
+                closed_at: None,
+                accrued_so_far: 0,
+                capitalized_interest: 0,
+                last_accrual_ts: seed.start_ts,
+                maturity_ts: seed.maturity_ts,
+                protection_deposit: 0,
+                rate_mode: RateMode::Fixed,
+                last_withdraw_ts: 0,
+                liquidations: Vec::new(),
+                status: LoanStatus::Active,
+            });
+            state.next_loan_id = state.next_loan_id.checked_add(1).expect("Loan id overflow");
+            state.total_collateral = state.total_collateral.checked_add(seed.collateral).expect("Collateral overflow");
+            state.total_principal = state.total_principal.checked_add(seed.principal).expect("Principal overflow");
+        }
+
+        self.emit_event(LendingEvent::LoansSeeded { count }).expect("Event err");
+        self.emit_event(LendingEvent::TotalsChanged {
+            total_collateral: state.total_collateral,
+            total_principal: state.total_principal,
+        }).expect("Event error");
+        LendingEvent::LoansSeeded { count }
+    }
+
+    /// Rebuild `user_loans` from the active loans in `loans` - owner only (session or self).
+    /// Scans up to `limit` loan ids starting at `from_loan_id`; a call with
+    /// `from_loan_id == 0` clears the index first. Repeat with the returned
+    /// `next_loan_id` until `complete` is true. Only while paused, so no loan is opened between
+    /// chunks; each loan is indexed at most once, so no borrower goes past MAX_USER_LOANS.
+    pub fn rebuild_user_index(
+        &mut self,
+        from_loan_id: u64,
+        limit: u64,
+        nonce: u64,
+        session_for_account: Option<ActorId>
+    ) -> LendingEvent {
+        let state = self.ensure_owner(nonce, session_for_account);
+        if !state.paused || !state.pending_opens.is_empty() {
+            panic!("Not paused");
+        }
+        if from_loan_id == 0 {
+            state.user_loans.clear();
+        }
+        let end = from_loan_id
+            .saturating_add(limit.min(MAX_REINDEX_BATCH))
+            .min(state.next_loan_id);
+        for loan_id in from_loan_id..end {
+            match state.loans.get(&loan_id) {
+                Some(loan) if loan.status == LoanStatus::Active => {
+                    reindex_loan(&mut state.user_loans, loan.borrower, loan_id);
+                }
+                _ => {}
+            }
+        }
+
+        let complete = end >= state.next_loan_id;
+        self.emit_event(LendingEvent::UserIndexRebuilt { next_loan_id: end, complete }).expect("Event err");
+        LendingEvent::UserIndexRebuilt { next_loan_id: end, complete }
+    }
+
+    // ---- Queries ----
+
+    /// Query: get loan by id
+    pub fn query_loan(&self, loan_id: u64) -> Option<Loan> {
+        LendingState::state_ref().loans.get(&loan_id).cloned()
+    }
+
+    /// Query: the loan's effective rate and its mode - the snapshot for fixed loans, the current
+    /// borrow rate for floating ones; `None` if the loan does not exist
+    pub fn query_loan_rate_info(&self, loan_id: u64) -> Option<(u128, RateMode)> {
+        let state = LendingState::state_ref();
+        let loan = state.loans.get(&loan_id)?;
+        Some((loan_rate(state, loan), loan.rate_mode))
+    }
+
+    /// Query: annualized effective rate of a loan in DECIMALS_FACTOR; `None` if the loan does
+    /// not exist. Interest accrues as simple interest on the principal, so without interest
+    /// capitalization this is the loan's nominal rate; with it, the rate compounded
+    /// CAPITALIZATIONS_PER_YEAR times a year, i.e. if `capitalize_interest` is called daily.
+    pub fn query_effective_apy(&self, loan_id: u64) -> Option<u128> {
+        let state = LendingState::state_ref();
+        let rate = loan_rate(state, state.loans.get(&loan_id)?);
+        Some(if state.interest_capitalization {
+            compounded_rate(rate, CAPITALIZATIONS_PER_YEAR)
+        } else {
+            rate
+        })
+    }
+
+    /// Query: debt tokens held as rounding reserve at the last `sync_rounding_reserve`
+    pub fn query_rounding_reserve(&self) -> u128 {
+        LendingState::state_ref().rounding_reserve
+    }
+
+    /// Query: collateral to add to an active loan to bring it back up to `min_collateral_ratio`
+    /// at the last known price, 0 if it is already safe. The protection deposit counts, since
+    /// liquidation applies it first. `None` for missing or closed loans, or when the collateral
+    /// has no borrowing power (zero price or collateral factor)
+    pub fn query_collateral_shortfall(&self, loan_id: u64) -> Option<u128> {
+        let state = LendingState::state_ref();
+        let loan = state.loans.get(&loan_id).filter(|loan| loan.status == LoanStatus::Active)?;
+        let price = last_known_price(state, state.collateral_token);
+        if price == 0 || state.collateral_factor == 0 {
+            return None;
         }
+        let required_power = mul_div_up(loan.principal, state.min_collateral_ratio, DECIMALS_FACTOR);
+        let required = mul_div_up(
+            mul_div_up(required_power, DECIMALS_FACTOR, state.collateral_factor),
+            DECIMALS_FACTOR,
+            price,
+        );
+        Some(required.saturating_sub(loan.collateral.saturating_add(loan.protection_deposit)))
     }
 
-    /// Repay a loan (with interest). Only authorized borrower via session or self.
-    pub async fn repay(
-        &mut self,
-        loan_id: u64,
-        session_for_account: Option<ActorId>,
-    ) -> LendingEvent {
-        let msg_src = msg::source();
-        let sessions = Storage::get_session_map();
-        let borrower = get_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::RepayLoan);
+    /// Query: loan-to-value of a loan in DECIMALS_FACTOR, principal over the borrowing power of
+    /// its collateral at the last known price (the reciprocal of its health factor, compared
+    /// against the config's `max_ltv`); `None` if the loan does not exist
+    pub fn query_ltv(&self, loan_id: u64) -> Option<u128> {
+        let state = LendingState::state_ref();
+        let loan = state.loans.get(&loan_id)?;
+        let price = last_known_price(state, state.collateral_token);
+        let power = borrowing_power(loan.collateral, price, state.collateral_factor);
+        Some(mul_div(loan.principal, DECIMALS_FACTOR, power.max(1)))
+    }
 
-        let mut state = LendingState::state_mut();
-        let loan = state.loans.get_mut(&loan_id).expect("No such loan");
-        if loan.borrower != borrower {
-            panic!("Not loan owner");
-        }
+    /// Query: collateral price (in DECIMALS_FACTOR, relative to the debt token) below which
+    /// an active loan becomes liquidatable, i.e.
+    /// `principal * min_collateral_ratio / (collateral * collateral_factor)`.
+    pub fn query_liquidation_price(&self, loan_id: u64) -> Option<u128> {
+        let state = LendingState::state_ref();
+        let loan = state.loans.get(&loan_id)?;
         if loan.status != LoanStatus::Active {
-            panic!("Loan not active");
+            return None;
         }
-        // Calculate interest
-        let current_block = exec::block_timestamp() as u64; 
-        let duration = current_block.saturating_sub(loan.start_block) as u128; 
-        let interest = loan
-            .principal
-            .saturating_mul(loan.interest_rate) 
-            .saturating_mul(duration)
-            .checked_div(31_536_000u128).unwrap_or(0)
-            .checked_div(DECIMALS_FACTOR).unwrap_or(0); 
-
-        let total_owed = loan.principal.saturating_add(interest); 
-
-        // Burn user debt tokens for repayment
-        let burn_debt = ActionIo::Burn(borrower, total_owed.into()).encode();
-        msg::send_bytes_with_gas_for_reply(state.debt_token, burn_debt, 5_000_000_000, 0, 0)
-            .expect("Burn failed")
-            .await
-            .expect("No reply debt burn");
+        liquidation_price(loan, state.min_collateral_ratio, state.collateral_factor)
+    }
 
-        // Return collateral to user
-        let transfer_coll = ActionIo::Transfer(borrower, loan.collateral.into()).encode();
-        msg::send_bytes_with_gas_for_reply(state.collateral_token, transfer_coll, 5_000_000_000, 0, 0)
-            .expect("Collateral transfer failed")
-            .await
-            .expect("No reply collateral transfer");
+    /// Query: up to `limit` active loans as `(loan_id, collateral_ratio)`, riskiest first.
+    /// Collects and sorts every active loan, so gas grows with the active loan count.
+    pub fn query_riskiest_loans(&self, limit: u32) -> Vec<(u64, u128)> {
+        let state = LendingState::state_ref();
+        let price = last_known_price(state, state.collateral_token);
+        let mut loans: Vec<(u64, u128)> = state
+            .loans
+            .iter()
+            .filter(|(_, loan)| loan.status == LoanStatus::Active)
+            .map(|(&id, loan)| {
+                (id, health_factor(loan.collateral, loan.principal, price, state.collateral_factor))
+            })
+            .collect();
+        loans.sort_unstable_by_key(|&(id, ratio)| (ratio, id));
+        loans.truncate(limit.min(MAX_QUERY_ITEMS) as usize);
+        loans
+    }
 
-        state.total_collateral = state.total_collateral.saturating_sub(loan.collateral); 
-        state.total_principal = state.total_principal.saturating_sub(loan.principal);
-        loan.status = LoanStatus::Closed;
+    /// Query: loans (any status) opened within `[from_ts, to_ts]`, lowest id first
+    pub fn query_loans_in_range(&self, from_ts: u64, to_ts: u64, limit: u32) -> Vec<(u64, Loan)> {
+        let state = LendingState::state_ref();
+        let mut loans: Vec<(u64, Loan)> = state
+            .loans
+            .iter()
+            .filter(|(_, loan)| (from_ts..=to_ts).contains(&loan.start_timestamp))
+            .map(|(&id, loan)| (id, loan.clone()))
+            .collect();
+        loans.sort_unstable_by_key(|(id, _)| *id);
+        loans.truncate(limit.min(MAX_QUERY_ITEMS) as usize);
+        loans
+    }
 
-        self.emit_event(LendingEvent::Repaid {
-            loan_id,
-            borrower,
-        }).expect("Event error"); 
+    /// Query: ids of active loans whose principal exceeds the current `max_loan`, lowest id first
+    pub fn query_over_max_loans(&self, limit: u32) -> Vec<u64> {
+        let state = LendingState::state_ref();
+        let mut ids: Vec<u64> = state
+            .loans
+            .iter()
+            .filter(|(_, loan)| loan.status == LoanStatus::Active && loan.principal > state.max_loan)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids.truncate(limit.min(MAX_QUERY_ITEMS) as usize);
+        ids
+    }
 
-        LendingEvent::Repaid {
-            loan_id,
-            borrower,
+    /// Query: how many of `user`'s loans were closed by liquidation
+    pub fn query_liquidation_count(&self, user: ActorId) -> u32 {
+        LendingState::state_ref().liquidation_counts.get(&user).copied().unwrap_or(0)
+    }
+
+    /// Query: collateral `account` can claim back (liquidation leftovers and refused payouts)
+    pub fn query_liquidation_surplus(&self, account: ActorId) -> u128 {
+        LendingState::state_ref().liquidation_surplus.get(&account).copied().unwrap_or(0)
+    }
+
+    /// Query: liquidation status of `loan_id` at the last known price; a `liquidate` call
+    /// refreshes the price first, so the outcome may still differ
+    pub fn query_liquidation_status(&self, loan_id: u64) -> LiquidationStatus {
+        let state = LendingState::state_ref();
+        let loan = match state.loans.get(&loan_id) {
+            Some(loan) if loan.status == LoanStatus::Active => loan,
+            _ => return LiquidationStatus::NotActive,
+        };
+        let price = last_known_price(state, state.collateral_token);
+        let now = exec::block_timestamp();
+        if health_factor(loan.collateral, loan.principal, price, state.collateral_factor) < state.min_collateral_ratio
+            || is_overdue(loan, now, state.post_maturity_grace_ms)
+        {
+            if loan.principal < state.min_liquidation_principal {
+                return LiquidationStatus::TooSmall;
+            }
+            return LiquidationStatus::Liquidatable;
+        }
+        match loan.maturity_ts {
+            Some(since) if since <= now => LiquidationStatus::Matured { since },
+            _ => LiquidationStatus::Safe,
         }
     }
 
-    /// Liquidate undercollateralized loan. Anyone can call; session not required.
-    pub async fn liquidate(
-        &mut self,
-        loan_id: u64,
-        _session_for_account: Option<ActorId>
-    ) -> LendingEvent {
-        // No session required on liquidation, but param included for interface consistency
-        let mut state = LendingState::state_mut();
-        let loan = state.loans.get_mut(&loan_id).expect("No loan");
-        if loan.status != LoanStatus::Active {
-            panic!("Loan not active");
+    /// Query: active term loans maturing within `within_ms` (or already past maturity), soonest
+    /// first, as `(loan_id, maturity_ts)`
+    pub fn query_maturing_soon(&self, within_ms: u64, limit: u32) -> Vec<(u64, u64)> {
+        let state = LendingState::state_ref();
+        let now = exec::block_timestamp();
+        let mut loans: Vec<(u64, u64)> = state
+            .loans
+            .iter()
+            .filter(|(_, loan)| loan.status == LoanStatus::Active)
+            .filter_map(|(&id, loan)| loan.maturity_ts.map(|maturity| (id, maturity)))
+            .filter(|&(_, maturity)| maturity.saturating_sub(now) <= within_ms)
+            .collect();
+        loans.sort_unstable_by_key(|&(id, maturity)| (maturity, id));
+        loans.truncate(limit.min(MAX_QUERY_ITEMS) as usize);
+        loans
+    }
+
+    /// Query: whether `account` has a session its key could use for `action` right now, under
+    /// the same checks as commands (action allowlist, expiry, `max_session_lifetime_ms`)
+    pub fn query_session_allows(&self, account: ActorId, action: ActionsForSession) -> bool {
+        let sessions = Storage::get_session_map();
+        usable_session(LendingState::state_ref(), sessions.get(&account), action, exec::block_timestamp()).is_ok()
+    }
+
+    /// Query: whether `update_params` would accept these values (first failing check, no mutation)
+    pub fn query_validate_params(
+        &self,
+        new_rate: u128,
+        min_loan: u128,
+        max_loan: u128,
+    ) -> Result<(), LendingError> {
+        validate_params(LendingState::state_ref(), new_rate, min_loan, max_loan)
+    }
+
+    /// Query: rough upper bound of the gas `op` needs: `call_gas_limit` per token call, the
+    /// oracle gas limit per price fetch (when an oracle is set) and a fixed overhead
+    pub fn query_estimated_gas(&self, op: OpKind) -> u64 {
+        let state = LendingState::state_ref();
+        let (token_calls, oracle_calls) = op.external_calls();
+        let oracle_gas = if state.price_oracle.is_some() { oracle_calls * ORACLE_GAS_LIMIT } else { 0 };
+        state
+            .call_gas_limit
+            .saturating_mul(token_calls)
+            .saturating_add(oracle_gas)
+            .saturating_add(OP_GAS_OVERHEAD)
+    }
+
+    /// Query: the contract's current time, `exec::block_timestamp()` in milliseconds. Interest
+    /// durations (e.g. `start_timestamp`, `last_accrual_ts`) are measured against this clock.
+    pub fn query_now(&self) -> u64 {
+        exec::block_timestamp()
+    }
+
+    /// Query: whether the lending state has been initialized
+    pub fn query_is_initialized(&self) -> bool {
+        LendingState::is_initialized()
+    }
+
+    /// Query: contract parameters, including `config_version`
+    pub fn query_config(&self) -> LendingConfig {
+        LendingState::state_ref().into()
+    }
+
+    /// Query: whether new borrowing is paused, and the reason given for it
+    pub fn query_pause_flags(&self) -> (bool, Option<Vec<u8>>) {
+        let state = LendingState::state_ref();
+        (state.paused, state.pause_reason.clone())
+    }
+
+    /// Query: token and oracle addresses with the verification status of the tokens
+    pub fn query_integrations(&self) -> Integrations {
+        let state = LendingState::state_ref();
+        Integrations {
+            collateral_token: state.collateral_token,
+            debt_token: state.debt_token,
+            price_oracle: state.price_oracle,
+            collateral_decimals: state.collateral_decimals,
+            debt_decimals: state.debt_decimals,
+            tokens_verified: state.tokens_verified,
         }
+    }
 
-        // Simulate on-chain price check for liquidation
-        let ratio = loan.collateral
-            .saturating_mul(DECIMALS_FACTOR)
-            .checked_div(loan.principal.max(1))
-            .expect("Division error");
-        if ratio >= MIN_COLLATERAL_RATIO {
-            panic!("Loan safe; can't liquidate");
+    /// Query: most that `collateral_amount` of `collateral_token` could borrow right now, bounded
+    /// by the minimum collateral ratio at the last known price, `max_loan` and, in pool mode,
+    /// available liquidity. Zero for any token other than the configured collateral
+    pub fn query_borrow_capacity(&self, collateral_token: ActorId, collateral_amount: u128) -> u128 {
+        let state = LendingState::state_ref();
+        if collateral_token != state.collateral_token {
+            return 0;
         }
+        let price = last_known_price(state, collateral_token);
+        let power = borrowing_power(collateral_amount, price, state.collateral_factor);
+        let capacity = mul_div(power, DECIMALS_FACTOR, state.min_collateral_ratio).min(state.max_loan);
+        // Without the pool new loans are not drawn from LP liquidity
+        if state.liquidity_pool_enabled {
+            capacity.min(available_liquidity(state))
+        } else {
+            capacity
+        }
+    }
 
-        // Collateral to contract owner as liquidator bonus
-        let transfer = ActionIo::Transfer(state.owner, loan.collateral.into()).encode();
-        msg::send_bytes_with_gas_for_reply(state.collateral_token, transfer, 5_000_000_000, 0, 0)
-            .expect("Collateral to owner failed")
-            .await
-            .expect("No reply on transfer");
+    /// Query: liquidity available for new loans (total liquidity minus outstanding principal)
+    pub fn query_available_liquidity(&self) -> u128 {
+        available_liquidity(LendingState::state_ref())
+    }
 
-        state.total_collateral = state.total_collateral.saturating_sub(loan.collateral);
-        state.total_principal = state.total_principal.saturating_sub(loan.principal); 
-        loan.status = LoanStatus::Liquidated;
+    /// Query: share of interest reserve recycled into liquidity, `compounded / (compounded + held)`
+    /// in DECIMALS_FACTOR; 0 when there is no reserve yet
+    pub fn query_reserve_utilization(&self) -> u128 {
+        let state = LendingState::state_ref();
+        let total = state.compounded_reserve.saturating_add(state.protocol_reserve);
+        if total == 0 {
+            return 0;
+        }
+        mul_div(state.compounded_reserve, DECIMALS_FACTOR, total)
+    }
 
-        self.emit_event(LendingEvent::Liquidated {
-            loan_id,
-            borrower: loan.borrower,
-        }).expect("Event error"); 
+    /// Query: lifetime protocol revenue (fees and reserve interest); never decreases
+    pub fn query_cumulative_revenue(&self) -> u128 {
+        LendingState::state_ref().cumulative_revenue
+    }
 
-        LendingEvent::Liquidated {
-            loan_id,
-            borrower: loan.borrower,
+    /// Query: debt tokens (issued, burned) by this contract, to cross-check against the token supply
+    pub fn query_debt_supply_delta(&self) -> (u128, u128) {
+        let state = LendingState::state_ref();
+        (state.total_debt_issued, state.total_debt_burned)
+    }
+
+    /// Query: principal-weighted average interest rate over all active loans, in
+    /// DECIMALS_FACTOR; the base rate when nothing is outstanding
+    pub fn query_twar(&self) -> u128 {
+        let state = LendingState::state_ref();
+        let (rate_weight, principal) = state
+            .loans
+            .values()
+            .filter(|loan| loan.status == LoanStatus::Active)
+            .fold((0u128, 0u128), |(weight, principal), loan| {
+                (
+                    weight.saturating_add(mul_div(loan.principal, loan_rate(state, loan), DECIMALS_FACTOR)),
+                    principal.saturating_add(loan.principal),
+                )
+            });
+        if principal == 0 {
+            return state.base_interest_rate;
         }
+        mul_div(rate_weight, DECIMALS_FACTOR, principal)
     }
 
-    /// Set new owner/admin (must be authorized owner, by session or self).
-    pub fn set_owner(
-        &mut self,
-        new_owner: ActorId,
-        session_for_account: Option<ActorId>
-    ) -> LendingEvent {
-        let msg_src = msg::source();
-        let sessions = Storage::get_session_map();
-        let who = get_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::UpdateParams);
+    /// Query: net interest margin in DECIMALS_FACTOR, the spread between the borrow rate and
+    /// the rate LPs earn on supplied liquidity:
+    /// `borrow_rate - borrow_rate * utilization * (1 - reserve_factor)`, where `borrow_rate` is
+    /// `query_twar` and `utilization = total_principal / total_liquidity` (0 without liquidity)
+    pub fn query_net_interest_margin(&self) -> u128 {
+        let state = LendingState::state_ref();
+        let borrow_rate = self.query_twar();
+        if state.total_liquidity == 0 {
+            return borrow_rate;
+        }
+        let utilization = utilization(state);
+        let lp_share = DECIMALS_FACTOR.saturating_sub(state.reserve_factor);
+        let supply_rate = mul_div(
+            mul_div(borrow_rate, utilization, DECIMALS_FACTOR),
+            lp_share,
+            DECIMALS_FACTOR,
+        );
+        borrow_rate.saturating_sub(supply_rate)
+    }
 
-        let mut state = LendingState::state_mut();
-        if who != state.owner {
-            panic!("Not owner");
+    /// Query: summary of a user's active loans at the current timestamp
+    pub fn query_position(&self, user: ActorId) -> Position {
+        let state = LendingState::state_ref();
+        let now = exec::block_timestamp();
+        let price = last_known_price(state, state.collateral_token);
+        let mut position = Position {
+            active_loans: 0,
+            total_collateral: 0,
+            total_principal: 0,
+            total_owed_now: 0,
+            weighted_avg_rate: 0,
+            worst_health_factor: u128::MAX,
+            weighted_health_factor: u128::MAX,
+        };
+        let mut rate_weight: u128 = 0;
+        let mut health_weight: u128 = 0;
+        let ids = state.user_loans.get(&user).map(|ids| ids.as_slice()).unwrap_or(&[]);
+        for id in ids.iter().take(MAX_USER_LOANS) {
+            let Some(loan) = state.loans.get(id) else { continue };
+            if loan.status != LoanStatus::Active {
+                continue;
+            }
+            position.active_loans += 1;
+            position.total_collateral = position.total_collateral.saturating_add(loan.collateral);
+            position.total_principal = position.total_principal.saturating_add(loan.principal);
+            position.total_owed_now = position
+                .total_owed_now
+                .saturating_add(total_owed(
+                    loan,
+                    now,
+                    state.interest_free_period_ms,
+                    state.max_interest_multiple,
+                    &state.floating_rate_checkpoints,
+                ));
+            rate_weight = rate_weight.saturating_add(mul_div(loan.principal, loan_rate(state, loan), DECIMALS_FACTOR));
+            let health = health_factor(loan.collateral, loan.principal, price, state.collateral_factor);
+            position.worst_health_factor = position.worst_health_factor.min(health);
+            health_weight = health_weight.saturating_add(mul_div(health, loan.collateral, DECIMALS_FACTOR));
         }
-        state.owner = new_owner;
-        self.emit_event(LendingEvent::OwnerSet(new_owner)).expect("Event err"); 
-        LendingEvent::OwnerSet(new_owner)
+        if position.total_principal > 0 {
+            position.weighted_avg_rate = mul_div(rate_weight, DECIMALS_FACTOR, position.total_principal);
+        }
+        if position.total_collateral > 0 {
+            position.weighted_health_factor = mul_div(health_weight, DECIMALS_FACTOR, position.total_collateral);
+        }
+        position
     }
 
-    /// Update lending params (base rate, min, max) - owner only (session or self).
-    pub fn update_params(
-        &mut self,
-        new_rate: u128,
-        min_loan: u128,
-        max_loan: u128,
-        session_for_account: Option<ActorId>
-    ) -> LendingEvent {
-        let msg_src = msg::source();
-        let sessions = Storage::get_session_map();
-        let who = get_actor(&sessions, &msg_src, &session_for_account, ActionsForSession::UpdateParams);
+    /// Query: the user's active loans aggregated in one read for a dashboard
+    pub fn query_user_position(&self, user: ActorId) -> UserPosition {
+        let position = self.query_position(user);
+        UserPosition {
+            total_collateral: position.total_collateral,
+            total_principal: position.total_principal,
+            total_interest_owed: position.total_owed_now.saturating_sub(position.total_principal),
+            weighted_health_factor: position.weighted_health_factor,
+            active_loans: position.active_loans,
+        }
+    }
 
-        let mut state = LendingState::state_mut();
-        if who != state.owner {
-            panic!("Not owner");
+    /// Query: solvency ratio `(collateral value + protocol reserve) / outstanding debt` in
+    /// DECIMALS_FACTOR, with interest accrued to now. Below DECIMALS_FACTOR the protocol is
+    /// insolvent; `u128::MAX` when nothing is outstanding.
+    pub fn query_solvency(&self) -> u128 {
+        let state = LendingState::state_ref();
+        let now = exec::block_timestamp();
+        let outstanding = state
+            .loans
+            .values()
+            .filter(|loan| loan.status == LoanStatus::Active)
+            .fold(0u128, |acc, loan| {
+                acc.saturating_add(total_owed(
+                    loan,
+                    now,
+                    state.interest_free_period_ms,
+                    state.max_interest_multiple,
+                    &state.floating_rate_checkpoints,
+                ))
+            });
+        if outstanding == 0 {
+            return u128::MAX;
         }
-        state.base_interest_rate = new_rate;
-        state.min_loan = min_loan;
-        state.max_loan = max_loan;
-        self.emit_event(LendingEvent::ParamsUpdated).expect("Event err");
-        LendingEvent::ParamsUpdated
+        let price = last_known_price(state, state.collateral_token);
+        let assets = collateral_value(state.total_collateral, price).saturating_add(state.protocol_reserve);
+        mul_div(assets, DECIMALS_FACTOR, outstanding)
     }
 
-    // ---- Queries (3) ----
+    /// Query: the last MAX_RATE_HISTORY base rate changes as `(timestamp, rate)`, oldest first
+    pub fn query_rate_history(&self) -> Vec<(u64, u128)> {
+        LendingState::state_ref().rate_history.clone()
+    }
 
-    /// Query: get loan by id
-    pub fn query_loan(&self, loan_id: u64) -> Option<Loan> {
-        LendingState::state_ref().loans.get(&loan_id).cloned()
+    /// Query: nonce the next owner-only call must pass
+    pub fn query_admin_nonce(&self) -> u64 {
+        LendingState::state_ref().admin_nonce
+    }
+
+    /// Query: id the next opened or seeded loan will get
+    pub fn query_next_loan_id(&self) -> u64 {
+        LendingState::state_ref().next_loan_id
+    }
+
+    /// Query: accumulated shortfall not covered by liquidated collateral
+    pub fn query_bad_debt(&self) -> u128 {
+        LendingState::state_ref().bad_debt
     }
 
-    /// Query: all loan ids for user
+    /// Query: ids of the user's open loans (closed loans stay queryable via `query_loan`)
     pub fn query_user_loans(&self, user: ActorId) -> Vec<u64> {
         let user_loans = LendingState::state_ref().user_loans.get(&user);
         match user_loans {
             Some(loans) => {
-                if loans.len() > 100 {
-                    loans[..100].to_vec() 
+                if loans.len() > MAX_USER_LOANS {
+                    loans[..MAX_USER_LOANS].to_vec() 
                 } else {
                     loans.clone()
                 }
@@ -479,6 +4457,78 @@ impl Service {
         }
     }
 
+    /// Query: page of distinct borrowers (accounts indexed in `user_loans`), at most
+    /// MAX_QUERY_ITEMS per call. The order is unspecified, but as long as no new borrower
+    /// is indexed between calls, consecutive pages cover every borrower exactly once.
+    pub fn query_borrowers(&self, start: u32, limit: u32) -> Vec<ActorId> {
+        LendingState::state_ref()
+            .user_loans
+            .keys()
+            .skip(start as usize)
+            .take(limit.min(MAX_QUERY_ITEMS) as usize)
+            .copied()
+            .collect()
+    }
+
+    /// Query: page of a user's loans with ids above `cursor` (`None` = from the first one), in
+    /// id order, plus the cursor of the next page (`None` once the list is exhausted). The cursor
+    /// is a loan id, so loans closed between calls do not shift later pages.
+    pub fn query_user_loans_cursor(&self, user: ActorId, cursor: Option<u64>, limit: u32) -> (Vec<(u64, Loan)>, Option<u64>) {
+        let state = LendingState::state_ref();
+        let mut ids: Vec<u64> = state
+            .user_loans
+            .get(&user)
+            .map(|ids| ids.iter().copied().filter(|id| cursor.is_none_or(|c| *id > c)).collect())
+            .unwrap_or_default();
+        ids.sort_unstable();
+        let limit = limit.min(MAX_QUERY_ITEMS) as usize;
+        let more = ids.len() > limit;
+        ids.truncate(limit);
+        let next = if more { ids.last().copied() } else { None };
+        let page = ids
+            .iter()
+            .filter_map(|id| state.loans.get(id).map(|loan| (*id, loan.clone())))
+            .collect();
+        (page, next)
+    }
+
+    /// Query: config, protocol stats and the loans with ids in `[loan_page_start, loan_page_start
+    /// + loan_page_limit)` in one call; the page is capped at MAX_DASHBOARD_LOANS ids
+    pub fn query_dashboard(&self, loan_page_start: u64, loan_page_limit: u32) -> Dashboard {
+        let state = LendingState::state_ref();
+        let end = loan_page_start
+            .saturating_add(loan_page_limit.min(MAX_DASHBOARD_LOANS) as u64)
+            .min(state.next_loan_id);
+        let loans = (loan_page_start..end)
+            .filter_map(|id| state.loans.get(&id).map(|loan| (id, loan.clone())))
+            .collect();
+        Dashboard {
+            config: self.query_config(),
+            stats: LendingStats {
+                total_collateral: state.total_collateral,
+                total_principal: state.total_principal,
+                available_liquidity: self.query_available_liquidity(),
+                protocol_reserve: state.protocol_reserve,
+                bad_debt: self.query_bad_debt(),
+                cumulative_revenue: self.query_cumulative_revenue(),
+                solvency: self.query_solvency(),
+                next_loan_id: self.query_next_loan_id(),
+            },
+            loans,
+        }
+    }
+
+    /// Query: contract state without truncation, for deployments with fewer than
+    /// MAX_FULL_STATE_LOANS loans; larger ones get `StateTooLarge` and should paginate
+    /// (e.g. `query_loans_in_range`) instead
+    pub fn query_full_state(&self) -> Result<IoLendingState, LendingError> {
+        let state = LendingState::state_ref();
+        if state.loans.len() >= MAX_FULL_STATE_LOANS {
+            return Err(LendingError::StateTooLarge);
+        }
+        Ok(IoLendingState::from(state))
+    }
+
     /// Query: contract state (full)
     pub fn query_state(&self) -> IoLendingState {
         let state = LendingState::state_ref();
@@ -503,6 +4553,525 @@ impl Service {
             user_loans: limited_user_loans,
             total_collateral: state.total_collateral,
             total_principal: state.total_principal,
+            interest_free_period_ms: state.interest_free_period_ms,
+            migration_mode: state.migration_mode,
+            max_loans: state.max_loans,
+            min_open_interval_ms: state.min_open_interval_ms,
+            bad_debt: state.bad_debt,
+            origination_fee: state.origination_fee,
+            referral_fee: state.referral_fee,
+            protocol_reserve: state.protocol_reserve,
+            price_oracle: state.price_oracle,
+            total_liquidity: state.total_liquidity,
+            lp_balances: state.lp_balances.iter().take(1000).map(|(&id, &v)| (id, v)).collect(),
+            reserve_factor: state.reserve_factor,
+            cumulative_revenue: state.cumulative_revenue,
+            min_liquidation_principal: state.min_liquidation_principal,
+            liquidation_surplus: state.liquidation_surplus.iter().take(1000).map(|(&id, &v)| (id, v)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_loan() -> Loan {
+        Loan {
+            borrower: ActorId::from([7u8; 32]),
+            collateral: 3_000,
+            principal: 1_000,
+            interest_rate: DECIMALS_FACTOR / 10,
+            start_timestamp: 1_700_000_000_000,
+            closed_at: Some(1_700_086_400_000),
+            accrued_so_far: 0,
+            capitalized_interest: 0,
+            last_accrual_ts: 1_700_000_000_000,
+            maturity_ts: None,
+            protection_deposit: 0,
+            rate_mode: RateMode::Fixed,
+            last_withdraw_ts: 0,
+            liquidations: Vec::new(),
+            status: LoanStatus::Closed,
+        }
+    }
+
+    #[test]
+    fn loan_decodes_timestamps_in_milliseconds() {
+        let loan = sample_loan();
+        let decoded_loan = Loan::decode(&mut loan.encode().as_slice()).expect("decodes");
+        assert_eq!(decoded_loan.start_timestamp, loan.start_timestamp);
+        assert_eq!(decoded_loan.closed_at, loan.closed_at);
+        // One day between open and close, expressed in milliseconds
+        assert_eq!(decoded_loan.closed_at.unwrap() - decoded_loan.start_timestamp, DAY_MS);
+    }
+
+    #[test]
+    fn to_token_amount_is_lossless() {
+        assert_eq!(to_token_amount(0), U256::zero());
+        assert_eq!(to_token_amount(u128::MAX), U256::from(u128::MAX));
+        assert_eq!(to_token_amount(u128::MAX).as_u128(), u128::MAX);
+    }
+
+    #[test]
+    fn whole_to_raw_scales_by_decimals() {
+        assert_eq!(whole_to_raw(7, 0), Some(7));
+        assert_eq!(whole_to_raw(5, 18), Some(5 * DECIMALS_FACTOR));
+        assert_eq!(whole_to_raw(1, 38), Some(10u128.pow(38)));
+        // 4e38 and 10^39 are both past u128::MAX (~3.4e38)
+        assert_eq!(whole_to_raw(4, 38), None);
+        assert_eq!(whole_to_raw(1, 39), None);
+    }
+
+    #[test]
+    fn compute_interest_skips_interest_free_period() {
+        let mut loan = sample_loan();
+        loan.principal = 1_000 * DECIMALS_FACTOR;
+        let start = loan.start_timestamp;
+        assert_eq!(compute_interest(&loan, start, 0, 0, &[]), 0);
+        assert_eq!(compute_interest(&loan, start + DAY_MS, DAY_MS, 0, &[]), 0);
+        assert!(compute_interest(&loan, start + 2 * DAY_MS, DAY_MS, 0, &[]) > 0);
+        // A timestamp before the last accrual charges nothing rather than underflowing
+        assert_eq!(compute_interest(&loan, start - 1, 0, 0, &[]), 0);
+    }
+
+    #[test]
+    fn compute_interest_charges_the_annual_rate_per_year() {
+        let mut loan = sample_loan();
+        loan.principal = 1_000 * DECIMALS_FACTOR;
+        // 10% over one year of milliseconds
+        let year_later = loan.start_timestamp + YEAR_MS as u64;
+        assert_eq!(compute_interest(&loan, year_later, 0, 0, &[]), 100 * DECIMALS_FACTOR);
+        assert_eq!(compute_interest(&loan, loan.start_timestamp + 365 * DAY_MS, 0, 0, &[]), 100 * DECIMALS_FACTOR);
+    }
+
+    #[test]
+    fn repayment_split_matches_owed_to_the_wei() {
+        let mut loan = sample_loan();
+        loan.principal = 1_000_000_007 * 1_000_000_000_000;
+        loan.interest_rate = DECIMALS_FACTOR / 20 + 3;
+        loan.status = LoanStatus::Active;
+        loan.closed_at = None;
+        let now = loan.start_timestamp + 12_345_678;
+        let owed = total_owed(&loan, now, 0, 0, &[]);
+
+        // floor(principal * rate * duration / (YEAR_MS * 1e18)), which does not divide evenly
+        settle_accrual(&mut loan, now, 0, 0, &[]);
+        assert_eq!(loan.accrued_so_far, 19_573_944_200_944_550);
+        let total = loan.principal + loan.accrued_so_far;
+        assert_eq!(total, owed);
+
+        // What `repay` burns plus the kept reserve share is exactly what is owed
+        let reserve_share = mul_div(loan.accrued_so_far, DECIMALS_FACTOR / 10, DECIMALS_FACTOR);
+        let burned = total - reserve_share;
+        assert_eq!(burned + reserve_share, owed);
+        assert_eq!(reserve_share, 1_957_394_420_094_455);
+    }
+
+    #[test]
+    fn throttle_liquidation_caps_each_window() {
+        let mut state = LendingState {
+            max_liquidations_per_window: 2,
+            liquidation_window_ms: 1_000,
+            ..Default::default()
+        };
+        assert_eq!(throttle_liquidation(&mut state, 0), Ok(()));
+        assert_eq!(throttle_liquidation(&mut state, 999), Ok(()));
+        assert_eq!(throttle_liquidation(&mut state, 999), Err(LendingError::LiquidationThrottled));
+        // A new window starts once the previous one has elapsed
+        assert_eq!(throttle_liquidation(&mut state, 1_000), Ok(()));
+        assert_eq!(state.liquidation_window_start, 1_000);
+        assert_eq!(state.liquidations_in_window, 1);
+
+        // Unlimited when the cap is 0
+        let mut state = LendingState::default();
+        for now in 0..10 {
+            assert_eq!(throttle_liquidation(&mut state, now), Ok(()));
+        }
+        assert_eq!(state.liquidations_in_window, 0);
+    }
+
+    #[test]
+    fn refund_liquidation_slot_only_refunds_the_current_window() {
+        let mut state = LendingState {
+            max_liquidations_per_window: 1,
+            liquidation_window_ms: 1_000,
+            ..Default::default()
+        };
+        assert_eq!(throttle_liquidation(&mut state, 0), Ok(()));
+        let window_start = state.liquidation_window_start;
+        refund_liquidation_slot(&mut state, window_start);
+        assert_eq!(state.liquidations_in_window, 0);
+        assert_eq!(throttle_liquidation(&mut state, 10), Ok(()));
+
+        // The slot was taken in a window that has since been replaced: nothing to give back
+        assert_eq!(throttle_liquidation(&mut state, 1_500), Ok(()));
+        refund_liquidation_slot(&mut state, window_start);
+        assert_eq!(state.liquidations_in_window, 1);
+    }
+
+    #[test]
+    fn checkpoint_floating_rate_records_rate_changes() {
+        let mut state = LendingState {
+            base_interest_rate: DECIMALS_FACTOR / 10,
+            ..Default::default()
+        };
+        let previous = current_borrow_rate(&state);
+        checkpoint_floating_rate(&mut state, previous, 1_000);
+        // Unchanged rate: nothing to record
+        assert!(state.floating_rate_checkpoints.is_empty());
+
+        state.base_interest_rate = DECIMALS_FACTOR / 5;
+        checkpoint_floating_rate(&mut state, previous, 1_000);
+        assert_eq!(state.floating_rate_checkpoints, [(1_000, 0, DECIMALS_FACTOR / 5)]);
+
+        let previous = current_borrow_rate(&state);
+        state.max_interest_rate = DECIMALS_FACTOR / 8;
+        checkpoint_floating_rate(&mut state, previous, 1_010);
+        assert_eq!(state.floating_rate_checkpoints[1], (1_010, 10 * DECIMALS_FACTOR / 5, DECIMALS_FACTOR / 8));
+
+        // A second change in the same block replaces the rate instead of adding a checkpoint
+        let previous = current_borrow_rate(&state);
+        state.max_interest_rate = 0;
+        checkpoint_floating_rate(&mut state, previous, 1_010);
+        assert_eq!(state.floating_rate_checkpoints.len(), 2);
+        assert_eq!(state.floating_rate_checkpoints[1].2, DECIMALS_FACTOR / 5);
+    }
+
+    #[test]
+    fn floating_loan_accrues_across_rate_changes() {
+        let mut loan = sample_loan();
+        loan.principal = 1_000 * DECIMALS_FACTOR;
+        loan.rate_mode = RateMode::Floating;
+        let start = loan.start_timestamp;
+        let half_year = YEAR_MS as u64 / 2;
+        // 10% until half a year in, 20% after
+        let checkpoints = [(start + half_year, 0, DECIMALS_FACTOR / 5)];
+        let year_later = start + 2 * half_year;
+        assert_eq!(compute_interest(&loan, year_later, 0, 0, &checkpoints), 150 * DECIMALS_FACTOR);
+        // Entirely before the change only the opening rate applies
+        assert_eq!(compute_interest(&loan, start + half_year, 0, 0, &checkpoints), 50 * DECIMALS_FACTOR);
+
+        settle_accrual(&mut loan, start + half_year, 0, 0, &checkpoints);
+        assert_eq!(loan.interest_rate, DECIMALS_FACTOR / 5);
+        assert_eq!(compute_interest(&loan, year_later, 0, 0, &checkpoints), 100 * DECIMALS_FACTOR);
+
+        // A fixed loan keeps its snapshot
+        let mut fixed = sample_loan();
+        fixed.principal = 1_000 * DECIMALS_FACTOR;
+        assert_eq!(compute_interest(&fixed, year_later, 0, 0, &checkpoints), 100 * DECIMALS_FACTOR);
+    }
+
+    #[test]
+    fn compute_interest_respects_max_multiple() {
+        let mut loan = sample_loan();
+        loan.interest_rate = 10 * DECIMALS_FACTOR;
+        let later = loan.start_timestamp + 365 * DAY_MS;
+        // Capped at half the principal, less what was already accrued
+        assert_eq!(compute_interest(&loan, later, 0, DECIMALS_FACTOR / 2, &[]), 500);
+        loan.accrued_so_far = 200;
+        assert_eq!(compute_interest(&loan, later, 0, DECIMALS_FACTOR / 2, &[]), 300);
+        loan.accrued_so_far = 600;
+        assert_eq!(compute_interest(&loan, later, 0, DECIMALS_FACTOR / 2, &[]), 0);
+    }
+
+    #[test]
+    fn restore_repay_amount_restores_min_ratio() {
+        let mut loan = sample_loan();
+        loan.collateral = 1_400;
+        loan.principal = 1_000;
+        let (price, cf, bonus, min_ratio) = (DECIMALS_FACTOR, DECIMALS_FACTOR, DECIMALS_FACTOR / 20, 3 * DECIMALS_FACTOR / 2);
+        let repay = restore_repay_amount(&loan, price, cf, bonus, min_ratio).expect("restorable");
+        // Shortfall of 100 over (150% - 105%), rounded up
+        assert_eq!(repay, 223);
+        let base = mul_div(repay, DECIMALS_FACTOR, price);
+        let seized = base + mul_div(base, bonus, DECIMALS_FACTOR);
+        assert!(health_factor(loan.collateral - seized, loan.principal - repay, price, cf) >= min_ratio);
+    }
+
+    #[test]
+    fn restore_repay_amount_covers_interest_first() {
+        let mut loan = sample_loan();
+        loan.collateral = 1_400;
+        loan.principal = 1_000;
+        let (price, cf, bonus, min_ratio) = (DECIMALS_FACTOR, DECIMALS_FACTOR, 0, 3 * DECIMALS_FACTOR / 2);
+        let without_interest = restore_repay_amount(&loan, price, cf, bonus, min_ratio).expect("restorable");
+        loan.accrued_so_far = 50;
+        let with_interest = restore_repay_amount(&loan, price, cf, bonus, min_ratio).expect("restorable");
+        assert!(with_interest > without_interest + 50);
+    }
+
+    #[test]
+    fn restore_repay_amount_none_when_seizing_cannot_help() {
+        let loan = sample_loan();
+        // Each unit repaid removes 150% in collateral, as much as the target ratio adds back
+        assert_eq!(
+            restore_repay_amount(&loan, DECIMALS_FACTOR, DECIMALS_FACTOR, DECIMALS_FACTOR / 2, 3 * DECIMALS_FACTOR / 2),
+            None
+        );
+    }
+
+    #[test]
+    fn expect_token_ok_accepts_only_a_true_reply() {
+        assert_eq!(expect_token_ok(true.encode()), Ok(()));
+        assert_eq!(expect_token_ok(false.encode()), Err(LendingError::TransferRejected));
+        // An empty or undecodable reply is a rejection too
+        assert_eq!(expect_token_ok(Vec::new()), Err(LendingError::TransferRejected));
+        assert_eq!(expect_token_ok([2u8].to_vec()), Err(LendingError::TransferRejected));
+    }
+
+    #[test]
+    fn use_admin_nonce_advances_on_the_current_nonce() {
+        let mut state = LendingState::default();
+        use_admin_nonce(&mut state, 0);
+        use_admin_nonce(&mut state, 1);
+        assert_eq!(state.admin_nonce, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "StaleNonce")]
+    fn use_admin_nonce_rejects_a_replayed_nonce() {
+        let mut state = LendingState::default();
+        use_admin_nonce(&mut state, 0);
+        use_admin_nonce(&mut state, 0);
+    }
+
+    #[test]
+    fn unindex_loan_keeps_the_order_of_the_other_loans() {
+        let borrower = ActorId::from([7u8; 32]);
+        let mut user_loans = SailsHashMap::default();
+        user_loans.insert(borrower, [1u64, 2, 3, 4].to_vec());
+        unindex_loan(&mut user_loans, borrower, 2);
+        assert_eq!(user_loans[&borrower], [1, 3, 4]);
+        // Unknown loans and borrowers are a no-op
+        unindex_loan(&mut user_loans, borrower, 9);
+        unindex_loan(&mut user_loans, ActorId::from([8u8; 32]), 1);
+        assert_eq!(user_loans[&borrower], [1, 3, 4]);
+    }
+
+    #[test]
+    fn reindex_loan_restores_id_order_once() {
+        let borrower = ActorId::from([7u8; 32]);
+        let mut user_loans = SailsHashMap::default();
+        user_loans.insert(borrower, [1u64, 3, 4].to_vec());
+        reindex_loan(&mut user_loans, borrower, 2);
+        reindex_loan(&mut user_loans, borrower, 2);
+        assert_eq!(user_loans[&borrower], [1, 2, 3, 4]);
+        reindex_loan(&mut user_loans, ActorId::from([8u8; 32]), 5);
+        assert_eq!(user_loans[&ActorId::from([8u8; 32])], [5]);
+    }
+
+    #[test]
+    fn is_overdue_only_after_maturity_plus_grace() {
+        let mut loan = sample_loan();
+        // Open-ended loans are never overdue
+        assert!(!is_overdue(&loan, u64::MAX, 0));
+        loan.maturity_ts = Some(1_000);
+        assert!(!is_overdue(&loan, 1_000 + DAY_MS - 1, DAY_MS));
+        assert!(is_overdue(&loan, 1_000 + DAY_MS, DAY_MS));
+        assert!(is_overdue(&loan, 1_000, 0));
+        // A huge grace period saturates instead of wrapping around
+        loan.maturity_ts = Some(u64::MAX - 1);
+        assert!(!is_overdue(&loan, u64::MAX - 1, u64::MAX));
+    }
+
+    #[test]
+    fn recomputed_totals_count_active_loans_and_their_deposits() {
+        let mut state = LendingState::default();
+        state.loans.insert(1, Loan { status: LoanStatus::Active, protection_deposit: 200, ..sample_loan() });
+        state.loans.insert(2, Loan { status: LoanStatus::Active, collateral: 500, principal: 250, ..sample_loan() });
+        // Closed and liquidated loans no longer count
+        state.loans.insert(3, sample_loan());
+        state.loans.insert(4, Loan { status: LoanStatus::Liquidated, ..sample_loan() });
+        assert_eq!(recomputed_totals(&state), (3_000 + 200 + 500, 1_000 + 250));
+    }
+
+    #[test]
+    fn health_factor_is_the_ratio_of_borrowing_power_to_principal() {
+        let price = 2 * DECIMALS_FACTOR;
+        let cf = 3 * DECIMALS_FACTOR / 4;
+        assert_eq!(collateral_value(3_000, price), 6_000);
+        assert_eq!(borrowing_power(3_000, price, cf), 4_500);
+        assert_eq!(collateral_ratio(3_000, 1_000), 3 * DECIMALS_FACTOR);
+        assert_eq!(health_factor(3_000, 1_000, price, cf), 9 * DECIMALS_FACTOR / 2);
+        // With no debt the ratio is taken against 1 instead of dividing by zero
+        assert_eq!(collateral_ratio(3_000, 0), 3_000 * DECIMALS_FACTOR);
+        assert_eq!(health_factor(0, 1_000, price, cf), 0);
+    }
+
+    #[test]
+    fn liquidation_price_is_where_health_meets_the_minimum() {
+        let loan = sample_loan();
+        let (min_ratio, cf) = (3 * DECIMALS_FACTOR / 2, 3 * DECIMALS_FACTOR / 4);
+        // 1000 * 150% / (3000 * 75%)
+        let price = liquidation_price(&loan, min_ratio, cf).expect("has a price");
+        assert_eq!(price, 666_666_666_666_666_666);
+        assert!(health_factor(loan.collateral, loan.principal, price, cf) < min_ratio);
+        assert!(health_factor(loan.collateral, loan.principal, price + 1_000, cf) >= min_ratio);
+
+        assert_eq!(liquidation_price(&Loan { collateral: 0, ..sample_loan() }, min_ratio, cf), None);
+        assert_eq!(liquidation_price(&loan, min_ratio, 0), None);
+    }
+
+    #[test]
+    fn utilization_and_available_liquidity_follow_the_pool() {
+        let mut state = LendingState::default();
+        assert_eq!(utilization(&state), 0);
+        assert_eq!(available_liquidity(&state), 0);
+
+        state.total_liquidity = 4_000;
+        state.total_principal = 1_000;
+        assert_eq!(utilization(&state), DECIMALS_FACTOR / 4);
+        assert_eq!(available_liquidity(&state), 3_000);
+
+        // Over-lent (e.g. after a write-off) saturates instead of going past 100%
+        state.total_principal = 5_000;
+        assert_eq!(utilization(&state), DECIMALS_FACTOR);
+        assert_eq!(available_liquidity(&state), 0);
+    }
+
+    #[test]
+    fn written_off_principal_is_not_lendable_again() {
+        let mut state = LendingState {
+            liquidity_pool_enabled: true,
+            total_liquidity: 4_000,
+            total_principal: 1_000,
+            ..Default::default()
+        };
+        // A seized loan of 600 leaves the books without repaying the pool
+        state.total_principal -= 600;
+        write_off_pool_principal(&mut state, 600);
+        assert_eq!(state.total_liquidity, 3_400);
+        assert_eq!(available_liquidity(&state), 3_000);
+
+        // Without the pool there is no LP liquidity to write off
+        let mut state = LendingState { total_liquidity: 4_000, ..Default::default() };
+        write_off_pool_principal(&mut state, 600);
+        assert_eq!(state.total_liquidity, 4_000);
+    }
+
+    #[test]
+    fn capitalized_interest_still_counts_against_the_cap() {
+        let mut loan = sample_loan();
+        loan.interest_rate = 10 * DECIMALS_FACTOR;
+        let later = loan.start_timestamp + 365 * DAY_MS;
+        // 200 of interest was capitalized: capped at half the lent 1000, less those 200
+        loan.principal = 1_200;
+        loan.capitalized_interest = 200;
+        assert_eq!(lent_principal(&loan), 1_000);
+        assert_eq!(compute_interest(&loan, later, 0, DECIMALS_FACTOR / 2, &[]), 300);
+        loan.accrued_so_far = 100;
+        assert_eq!(settled_interest(&loan), 300);
+        assert_eq!(compute_interest(&loan, later, 0, DECIMALS_FACTOR / 2, &[]), 200);
+    }
+
+    #[test]
+    fn compounded_rate_matches_daily_compounding() {
+        // (1 + 10% / 365)^365 - 1 = 10.5155781616...%
+        assert_eq!(compounded_rate(DECIMALS_FACTOR / 10, 365), 105_155_781_616_264_095);
+        // Compounding once a year is the nominal rate
+        assert_eq!(compounded_rate(DECIMALS_FACTOR / 10, 1), DECIMALS_FACTOR / 10);
+        assert_eq!(compounded_rate(0, 365), 0);
+    }
+
+    #[test]
+    fn external_calls_cover_the_worst_case_path() {
+        let expected = [
+            (OpKind::OpenLoan, (4, 1)),
+            (OpKind::Repay, (4, 0)),
+            (OpKind::RepayWithCollateral, (3, 1)),
+            (OpKind::ConsolidateAndRepay, (4, 0)),
+            (OpKind::FundProtection, (2, 0)),
+            (OpKind::Liquidate, (5, 1)),
+            (OpKind::LiquidateAmount, (5, 1)),
+            (OpKind::DepositLiquidity, (1, 0)),
+            (OpKind::WithdrawLiquidity, (1, 0)),
+            (OpKind::AddCollateral, (2, 0)),
+            (OpKind::WithdrawCollateral, (2, 1)),
+        ];
+        for (op, calls) in expected {
+            assert_eq!(op.external_calls(), calls, "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn max_ltv_round_trips_through_the_min_ratio() {
+        for ltv in [
+            7 * DECIMALS_FACTOR / 10,
+            8 * DECIMALS_FACTOR / 10,
+            DECIMALS_FACTOR / 3,
+            DECIMALS_FACTOR - 1,
+            1,
+        ] {
+            let min_ratio = min_ratio_for_ltv(ltv);
+            assert_eq!(max_ltv(min_ratio), ltv);
+            // The ratio view is never looser than the LTV asked for
+            assert!(mul_div(ltv, min_ratio, DECIMALS_FACTOR) >= DECIMALS_FACTOR);
         }
+        assert_eq!(min_ratio_for_ltv(7 * DECIMALS_FACTOR / 10), 1_428_571_428_571_428_572);
+        assert_eq!(max_ltv(3 * DECIMALS_FACTOR / 2), 666_666_666_666_666_667);
+    }
+
+    #[test]
+    fn validate_params_rejects_bad_bounds_and_rates() {
+        let state = LendingState {
+            min_interest_rate: DECIMALS_FACTOR / 100,
+            max_interest_rate: DECIMALS_FACTOR / 5,
+            ..Default::default()
+        };
+        let rate = DECIMALS_FACTOR / 10;
+        assert_eq!(validate_params(&state, rate, 1, 1_000), Ok(()));
+        assert_eq!(validate_params(&state, rate, 1_000, 1_000), Ok(()));
+        assert_eq!(validate_params(&state, rate, 0, 1_000), Err(LendingError::InvalidLoanBounds));
+        assert_eq!(validate_params(&state, rate, 1_001, 1_000), Err(LendingError::InvalidLoanBounds));
+        assert_eq!(
+            validate_params(&state, DECIMALS_FACTOR / 200, 1, 1_000),
+            Err(LendingError::RateOutOfBounds)
+        );
+        assert_eq!(
+            validate_params(&state, DECIMALS_FACTOR / 4, 1, 1_000),
+            Err(LendingError::RateOutOfBounds)
+        );
+        // No ceiling when max_interest_rate is 0
+        let uncapped = LendingState { max_interest_rate: 0, ..state };
+        assert_eq!(validate_params(&uncapped, DECIMALS_FACTOR, 1, 1_000), Ok(()));
+    }
+
+    #[test]
+    fn compacting_checkpoints_keeps_later_indexes() {
+        let rate = DECIMALS_FACTOR / 10;
+        let mut checkpoints = Vec::new();
+        checkpoints.push((1_000, 0, rate));
+        checkpoints.push((1_010, 10 * rate, 3 * rate));
+        checkpoints.push((1_020, 40 * rate, rate));
+        checkpoints.push((1_030, 50 * rate, 2 * rate));
+        let later = floating_rate_integral(&checkpoints, rate, 1_020, 1_050);
+
+        compact_floating_checkpoints(&mut checkpoints);
+        assert_eq!(checkpoints.len(), 3);
+        // 40 * rate over 20 ms: the average of the two merged rates
+        assert_eq!(checkpoints[0], (1_000, 0, 2 * rate));
+        assert_eq!(floating_rate_index(&checkpoints, 1_020), 40 * rate);
+        assert_eq!(floating_rate_integral(&checkpoints, rate, 1_020, 1_050), later);
+        assert_eq!(floating_rate_integral(&checkpoints, rate, 990, 1_020), 50 * rate);
+    }
+
+    #[test]
+    fn cached_price_is_refused_once_too_old() {
+        let token = ActorId::from([7u8; 32]);
+        let mut cached_prices = SailsHashMap::default();
+        cached_prices.insert(token, (2 * DECIMALS_FACTOR, 1_000));
+        let state = LendingState {
+            cached_prices,
+            max_price_age_ms: 500,
+            ..Default::default()
+        };
+        assert_eq!(cached_price(&state, token, 1_000), Ok(2 * DECIMALS_FACTOR));
+        assert_eq!(cached_price(&state, token, 1_500), Ok(2 * DECIMALS_FACTOR));
+        assert_eq!(cached_price(&state, token, 1_501), Err(LendingError::StalePrice));
+        assert_eq!(cached_price(&state, ActorId::from([8u8; 32]), 1_000), Err(LendingError::OracleUnavailable));
+        // 0 never falls back to the cache
+        let no_fallback = LendingState { max_price_age_ms: 0, ..state };
+        assert_eq!(cached_price(&no_fallback, token, 1_000), Err(LendingError::StalePrice));
     }
 }